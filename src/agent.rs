@@ -0,0 +1,122 @@
+use serde_json::Value;
+
+use crate::{
+    chat::function_responses, content::Content, error::GeminiError, model::GenerativeModel,
+};
+
+/// The outcome of [GenerativeModel::run_agent].
+#[derive(Debug)]
+pub struct AgentRun {
+    /// The model's final answer, or `None` if `max_turns` was reached first.
+    pub text: Option<String>,
+    /// Every turn of the conversation, including function calls and their responses.
+    pub transcript: Vec<Content>,
+}
+
+impl GenerativeModel {
+    /// Runs a tool-calling loop until the model gives a final answer.
+    ///
+    /// Each turn, every function call the model makes is passed to `dispatcher` with its
+    /// name and arguments, and the results are sent back in a single turn. The loop ends
+    /// when the model replies without calling any function, or after `max_turns` requests.
+    pub async fn run_agent(
+        &self,
+        initial: Vec<Content>,
+        dispatcher: impl Fn(&str, &Value) -> Value,
+        max_turns: usize,
+    ) -> Result<AgentRun, GeminiError> {
+        let mut transcript = initial;
+        for _ in 0..max_turns {
+            let response = self.generate_content(transcript.clone()).await?;
+            let candidate = response
+                .candidates
+                .into_iter()
+                .next()
+                .ok_or_else(|| GeminiError::message("the response has no candidates"))?;
+
+            let results: Vec<_> = candidate
+                .function_calls()
+                .into_iter()
+                .map(|(name, args)| {
+                    let result = dispatcher(name, args.unwrap_or(&Value::Null));
+                    (name.to_string(), result)
+                })
+                .collect();
+            let text = candidate.text();
+            transcript.push(candidate.content);
+
+            if results.is_empty() {
+                return Ok(AgentRun { text, transcript });
+            }
+            transcript.push(function_responses(results));
+        }
+
+        Ok(AgentRun {
+            text: None,
+            transcript,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use serde_json::json;
+
+    use crate::{content::Part, mock::MockServer};
+
+    #[tokio::test]
+    async fn two_turn_agent_loop() {
+        let server = MockServer::start(vec![
+            (
+                200,
+                json!({
+                    "candidates": [{ "content": { "role": "model", "parts": [
+                        { "functionCall": { "name": "add", "args": { "a": 2, "b": 3 } } }
+                    ] } }],
+                    "usageMetadata": {}
+                })
+                .to_string(),
+            ),
+            (
+                200,
+                json!({
+                    "candidates": [{ "content": { "role": "model", "parts": [{ "text": "2 + 3 = 5" }] } }],
+                    "usageMetadata": {}
+                })
+                .to_string(),
+            ),
+        ])
+        .await;
+        let model = server.model().build();
+
+        let calls = RefCell::new(Vec::new());
+        let run = model
+            .run_agent(
+                vec!["what is 2 + 3?".into()],
+                |name, args| {
+                    calls.borrow_mut().push(name.to_string());
+                    json!({ "sum": args["a"].as_i64().unwrap() + args["b"].as_i64().unwrap() })
+                },
+                5,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(run.text.as_deref(), Some("2 + 3 = 5"));
+        assert_eq!(calls.into_inner(), ["add"]);
+        assert_eq!(run.transcript.len(), 4);
+        assert!(matches!(
+            &run.transcript[2].parts[0],
+            Part::FunctionResponse { name, response } if name == "add" && response["sum"] == 5
+        ));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[1].json()["contents"][2]["parts"][0]["functionResponse"]["response"]["sum"],
+            5
+        );
+    }
+}
@@ -1,12 +1,18 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    content::{Content, Part},
-    grounding::{GoogleSearchRetrieval, GroundingAtrribution},
+    content::Content,
+    error::GeminiError,
+    grounding::{
+        self, DynamicRetrievalConfig, GoogleSearchRetrieval, GroundingAtrribution,
+        GroundingMetadata,
+    },
     schema::Schema,
 };
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Candidate {
     /// Generated content returned from the model.
@@ -15,6 +21,7 @@ pub struct Candidate {
     /// List of ratings for the safety of a response candidate.
     ///
     /// There is at most one rating per category.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub safety_ratings: Option<Vec<SafetyRating>>,
 
     /// Citation information for model-generated candidate.
@@ -22,14 +29,26 @@ pub struct Candidate {
     /// This field may be populated with recitation information for any text
     /// included in the [content]. These are passages that are "recited" from
     /// copyrighted material in the foundational LLM's training data.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub citation_metadata: Option<CitationMetadata>,
 
     /// The reason why the model stopped generating tokens.
     ///
     /// If None, the model has not stopped generating the tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub finish_reason: Option<FinishReason>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub grounding_attributions: Option<Vec<GroundingAtrribution>>,
+
+    /// The sources used to ground this candidate, when grounding (e.g. Google Search) was enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grounding_metadata: Option<GroundingMetadata>,
+
+    /// The average log probability of this candidate's tokens; higher means the
+    /// model was more confident.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_logprobs: Option<f64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -41,7 +60,7 @@ pub struct SafetyRating {
     probability: HarmProbability,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum HarmCategory {
     #[serde(rename = "HARM_CATEGORY_UNSPECIFIED")]
     Unspecified,
@@ -62,9 +81,24 @@ pub enum HarmCategory {
     /// Promotes or enables access to harmful goods, services, and activities.
     #[serde(rename = "HARM_CATEGORY_DANGEROUS_CONTENT")]
     DangerousContent,
+
+    /// Content that may be used to harm civic integrity, e.g. election misinformation.
+    #[serde(rename = "HARM_CATEGORY_CIVIC_INTEGRITY")]
+    CivicIntegrity,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+impl HarmCategory {
+    /// Every category that can be configured with a [SafetySetting].
+    pub const ALL: [HarmCategory; 5] = [
+        HarmCategory::Harassment,
+        HarmCategory::HateSpeech,
+        HarmCategory::SexuallyExplicit,
+        HarmCategory::DangerousContent,
+        HarmCategory::CivicIntegrity,
+    ];
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum HarmProbability {
     /// Probability is unspecified.
@@ -83,22 +117,23 @@ pub enum HarmProbability {
     High,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CitationMetadata {
     pub citation_sources: Vec<CitationSource>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CitationSource {
     pub start_index: i32,
     pub end_index: i32,
     pub uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub license: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum FinishReason {
     #[serde(rename = "FINISH_REASON_UNSPECIFIED")]
     Unspecified,
@@ -106,7 +141,7 @@ pub enum FinishReason {
     Stop,
     #[serde(rename = "MAX_TOKENS")]
     MaxTokens,
-    #[serde(rename = "SAFTEY")]
+    #[serde(rename = "SAFETY")]
     Safety,
     #[serde(rename = "RECITATION")]
     Recitation,
@@ -130,6 +165,59 @@ pub struct SafetySetting {
     pub threshold: HarmBlockThreshold,
 }
 
+/// A list of [SafetySetting]s holding at most one setting per [HarmCategory].
+#[derive(Debug, Clone, Default)]
+pub struct SafetySettings(Vec<SafetySetting>);
+
+impl SafetySettings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the threshold for `category`, replacing any threshold set for it before.
+    pub fn set(&mut self, category: HarmCategory, threshold: HarmBlockThreshold) -> &mut Self {
+        match self
+            .0
+            .iter_mut()
+            .find(|setting| setting.category == category)
+        {
+            Some(setting) => setting.threshold = threshold,
+            None => self.0.push(SafetySetting {
+                category,
+                threshold,
+            }),
+        }
+        self
+    }
+
+    pub fn as_slice(&self) -> &[SafetySetting] {
+        &self.0
+    }
+}
+
+impl From<SafetySettings> for Vec<SafetySetting> {
+    fn from(value: SafetySettings) -> Self {
+        value.0
+    }
+}
+
+/// Checks that no [HarmCategory] is configured twice, since the API would then pick one
+/// of the thresholds unpredictably.
+pub(crate) fn validate_safety_settings(settings: &[SafetySetting]) -> Result<(), GeminiError> {
+    for (i, setting) in settings.iter().enumerate() {
+        if settings[..i]
+            .iter()
+            .any(|other| other.category == setting.category)
+        {
+            return Err(GeminiError::invalid_argument(&format!(
+                "duplicate safety setting for {:?}",
+                setting.category
+            )));
+        }
+    }
+    Ok(())
+}
+
 /// Probability of harm which causes content to be blocked.
 ///
 /// When provided in [SafetySetting.threshold], a predicted harm probability at
@@ -157,49 +245,117 @@ pub enum HarmBlockThreshold {
     None,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum BlockReason {
     #[serde(rename = "BLOCK_REASON_UNSPECIFIED")]
     Unspecified,
-    #[serde(rename = "SAFTEY")]
+    #[serde(rename = "SAFETY")]
     Saftey,
     #[serde(rename = "OTHER")]
     Other,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct PromptFeedback {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub block_reason: Option<BlockReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub block_reason_message: Option<String>,
-    pub saftey_ratings: Vec<SafetyRating>,
+    /// Ratings for the safety of the prompt; the API may omit them when blocking it.
+    #[serde(default)]
+    pub safety_ratings: Vec<SafetyRating>,
 }
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct UsageMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub prompt_token_count: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub candidates_token_count: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub cached_content_token_count: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub total_token_count: Option<i32>,
 }
 
-#[derive(Debug, Deserialize)]
+impl UsageMetadata {
+    /// The number of tokens in the prompt, or 0 if not reported.
+    pub fn prompt_tokens(&self) -> i32 {
+        self.prompt_token_count.unwrap_or(0)
+    }
+
+    /// The number of tokens in the generated candidates, or 0 if not reported.
+    pub fn candidate_tokens(&self) -> i32 {
+        self.candidates_token_count.unwrap_or(0)
+    }
+
+    /// The number of prompt tokens served from cached content, or 0 if not reported.
+    pub fn cached_tokens(&self) -> i32 {
+        self.cached_content_token_count.unwrap_or(0)
+    }
+
+    /// The total number of tokens, or 0 if not reported.
+    pub fn total_tokens(&self) -> i32 {
+        self.total_token_count.unwrap_or(0)
+    }
+
+    /// The total number of tokens minus those served from cached content.
+    pub fn billable_tokens(&self) -> i32 {
+        self.total_tokens() - self.cached_tokens()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ContentEmbedding {
     pub values: Vec<f64>,
 }
 
+#[cfg(feature = "ndarray")]
+impl ContentEmbedding {
+    /// Copies the values into an [ndarray::Array1], e.g. for vector math.
+    pub fn to_array1(&self) -> ndarray::Array1<f64> {
+        ndarray::Array1::from_vec(self.values.clone())
+    }
+
+    /// Converts the embedding into an [ndarray::Array1] without copying the values.
+    pub fn into_array1(self) -> ndarray::Array1<f64> {
+        ndarray::Array1::from_vec(self.values)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct CountTokenResponse {
     pub total_tokens: i32,
 }
 
 impl Candidate {
+    /// Returns the `(name, args)` of every function call the model made, in order.
+    ///
+    /// The model may request several calls in one turn to have them run in parallel.
+    pub fn function_calls(&self) -> Vec<(&str, Option<&serde_json::Value>)> {
+        self.content.function_call_parts()
+    }
+
+    /// Returns the probability of harm the API rated for each category.
+    ///
+    /// Categories that weren't rated (e.g. because the candidate has no ratings) are absent.
+    pub fn safety_ratings_map(&self) -> HashMap<HarmCategory, HarmProbability> {
+        self.safety_ratings
+            .iter()
+            .flatten()
+            .map(|rating| (rating.category.clone(), rating.probability.clone()))
+            .collect()
+    }
+
+    /// Returns the text of the candidate, or `None` if it is empty.
+    ///
+    /// This doesn't tell a candidate without text parts (e.g. one holding only safety
+    /// metadata or function calls) from one whose text is empty; use
+    /// [Candidate::text_opt] for that.
     pub fn text(&self) -> Option<String> {
-        let mut text = String::new();
-        for part in &self.content.parts {
-            if let Part::Text(t) = part {
-                text.push_str(t);
-            }
-        }
+        let text = self.content.text_parts().concat();
 
         if text.is_empty() {
             None
@@ -207,6 +363,18 @@ impl Candidate {
             Some(text)
         }
     }
+
+    /// Returns the text of the candidate, or `None` if it has no text parts at all.
+    ///
+    /// Unlike [Candidate::text], text parts that are all empty give `Some("")`.
+    pub fn text_opt(&self) -> Option<String> {
+        let parts = self.content.text_parts();
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.concat())
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Clone, Default)]
@@ -220,6 +388,51 @@ pub struct Tool {
     pub code_execution: Option<CodeExecution>,
 }
 
+impl Tool {
+    /// A tool declaring a single function.
+    pub fn function(name: &str, description: &str, parameters: Schema) -> Self {
+        Self::functions(vec![FunctionDeclaration {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters: Some(parameters),
+        }])
+    }
+
+    /// A tool declaring several functions.
+    pub fn functions(declarations: Vec<FunctionDeclaration>) -> Self {
+        Self {
+            function_declarations: Some(declarations),
+            ..Default::default()
+        }
+    }
+
+    /// A tool letting the model ground its answers with Google Search, when it decides to.
+    pub fn google_search() -> Self {
+        Self {
+            google_search_retrieval: Some(GoogleSearchRetrieval {
+                dynamic_retrieval_config: DynamicRetrievalConfig {
+                    mode: grounding::Mode::ModeDynamic,
+                    dynamic_threshold: None,
+                },
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// A tool letting the model write and run Python code to compute its answers.
+    ///
+    /// Responses then contain [Part::ExecutableCode] and [Part::CodeExecutionResult] parts.
+    ///
+    /// [Part::ExecutableCode]: crate::content::Part::ExecutableCode
+    /// [Part::CodeExecutionResult]: crate::content::Part::CodeExecutionResult
+    pub fn code_execution() -> Self {
+        Self {
+            code_execution: Some(CodeExecution {}),
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub struct ToolConfig {
     function_calling_config: Option<FunctionCallingConfig>,
@@ -242,8 +455,8 @@ pub enum Mode {
     None,
 }
 
-#[derive(Debug, Serialize, Clone)]
-pub struct CodeExecution;
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct CodeExecution {}
 
 #[derive(Debug, Serialize, Clone, Default)]
 #[serde(rename_all = "camelCase")]
@@ -252,8 +465,15 @@ pub struct GenerationConfig {
     pub stop_sequences: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub response_mime_type: Option<ResponseMimeType>,
+    /// Output schema for JSON responses, in the OpenAPI subset understood by all models.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub response_schema: Option<Schema>,
+    /// Output schema for JSON responses as a full JSON Schema document.
+    ///
+    /// Use this instead of `response_schema` when the schema needs features the
+    /// [Schema] type can't express, such as `$ref` or `anyOf`. Only one of the two may be set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_json_schema: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub candidate_count: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -263,18 +483,100 @@ pub struct GenerationConfig {
     pub temperature: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_p: Option<f64>,
+    /// Only sample from the `top_k` most likely tokens. Must be at least 1.
+    ///
+    /// The maximum depends on the model (e.g. 40 for Gemini 1.0, 64 for Gemini 1.5 Flash)
+    /// and is enforced by the API.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_k: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub presence_penalty: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub frequence_penalty: Option<f32>,
+    /// Whether to return the log probability of each chosen token with the response.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub response_logprobs: Option<bool>,
+    /// How many of the most likely alternative tokens to return with each chosen token, from 0 to 20.
+    ///
+    /// Only takes effect when `response_logprobs` is `Some(true)`; setting it otherwise is an error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<i32>,
+}
+
+impl GenerationConfig {
+    /// A preset that makes the model pick the most likely token every time.
+    ///
+    /// Sets `temperature` to `0.0` and `top_k` to `1`.
+    pub fn deterministic() -> Self {
+        Self {
+            temperature: Some(0.0),
+            top_k: Some(1),
+            ..Default::default()
+        }
+    }
+
+    /// A preset for general use, trading off focus and variety.
+    ///
+    /// Sets `temperature` to `0.7`, `top_p` to `0.95` and `top_k` to `40`.
+    pub fn balanced() -> Self {
+        Self {
+            temperature: Some(0.7),
+            top_p: Some(0.95),
+            top_k: Some(40),
+            ..Default::default()
+        }
+    }
+
+    /// A preset for brainstorming and creative writing.
+    ///
+    /// Sets `temperature` to `1.3`, `top_p` to `0.98` and `top_k` to `64`.
+    pub fn creative() -> Self {
+        Self {
+            temperature: Some(1.3),
+            top_p: Some(0.98),
+            top_k: Some(64),
+            ..Default::default()
+        }
+    }
+
+    /// Checks the configuration for mistakes the API would reject.
+    pub(crate) fn validate(&self) -> Result<(), GeminiError> {
+        if self.response_schema.is_some() && self.response_json_schema.is_some() {
+            return Err(GeminiError::invalid_argument(
+                "response_schema and response_json_schema are mutually exclusive",
+            ));
+        }
+        if let Some(count) = self.candidate_count {
+            if !(1..=8).contains(&count) {
+                return Err(GeminiError::invalid_argument(&format!(
+                    "candidate_count must be between 1 and 8, got {count}"
+                )));
+            }
+        }
+        if let Some(top_k) = self.top_k {
+            if top_k < 1 {
+                return Err(GeminiError::invalid_argument(&format!(
+                    "top_k must be at least 1, got {top_k}"
+                )));
+            }
+        }
+        if let Some(logprobs) = self.logprobs {
+            if !(0..=20).contains(&logprobs) {
+                return Err(GeminiError::invalid_argument(&format!(
+                    "logprobs must be between 0 and 20, got {logprobs}"
+                )));
+            }
+            if self.response_logprobs != Some(true) {
+                return Err(GeminiError::invalid_argument(
+                    "logprobs requires response_logprobs to be enabled",
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Serialize, Clone)]
-#[serde(rename = "camelCase")]
 pub enum ResponseMimeType {
     #[serde(rename = "text/plain")]
     TextPlain,
@@ -309,13 +611,259 @@ pub enum TaskType {
     FactVerification,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GeminiGenericErrorResponse {
     pub(crate) error: GeminiGenericError,
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GeminiGenericError {
     pub code: i32,
     pub message: String,
+    /// Missing from errors nested in other responses, such as batch results.
+    #[serde(default)]
     pub status: String,
+    /// Structured details about the error, such as the quota that was exceeded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<serde_json::Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Type;
+
+    #[test]
+    fn response_json_schema_serializes() {
+        let config = GenerationConfig {
+            response_json_schema: Some(serde_json::json!({
+                "anyOf": [{ "type": "string" }, { "$ref": "#/$defs/item" }]
+            })),
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(
+            json["responseJsonSchema"]["anyOf"][1]["$ref"],
+            "#/$defs/item"
+        );
+        assert!(json.get("responseSchema").is_none());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn billable_tokens_subtracts_cached() {
+        let usage = UsageMetadata {
+            prompt_token_count: Some(1000),
+            candidates_token_count: Some(200),
+            cached_content_token_count: Some(600),
+            total_token_count: Some(1200),
+        };
+        assert_eq!(usage.cached_tokens(), 600);
+        assert_eq!(usage.billable_tokens(), 600);
+
+        let usage = UsageMetadata {
+            prompt_token_count: Some(10),
+            candidates_token_count: None,
+            cached_content_token_count: None,
+            total_token_count: Some(10),
+        };
+        assert_eq!(usage.candidate_tokens(), 0);
+        assert_eq!(usage.billable_tokens(), 10);
+    }
+
+    #[test]
+    fn function_tool() {
+        let parameters = Schema::new(Type::Object);
+        let tool = Tool::function("get_weather", "Gets the weather", parameters);
+
+        let declarations = tool.function_declarations.unwrap();
+        assert_eq!(declarations.len(), 1);
+        assert_eq!(declarations[0].name, "get_weather");
+        assert_eq!(declarations[0].description, "Gets the weather");
+        assert!(matches!(
+            declarations[0].parameters,
+            Some(Schema {
+                schema_type: Type::Object,
+                ..
+            })
+        ));
+        assert!(tool.google_search_retrieval.is_none());
+    }
+
+    #[test]
+    fn code_execution_tool() {
+        assert_eq!(
+            serde_json::to_value(Tool::code_execution()).unwrap(),
+            serde_json::json!({ "codeExecution": {} })
+        );
+    }
+
+    #[test]
+    fn response_mime_type_values() {
+        assert_eq!(
+            serde_json::to_string(&ResponseMimeType::TextPlain).unwrap(),
+            r#""text/plain""#
+        );
+        assert_eq!(
+            serde_json::to_string(&ResponseMimeType::ApplicationJson).unwrap(),
+            r#""application/json""#
+        );
+    }
+
+    #[test]
+    fn prompt_feedback_without_ratings() {
+        let feedback: PromptFeedback =
+            serde_json::from_value(serde_json::json!({ "blockReason": "SAFETY" })).unwrap();
+        assert!(matches!(feedback.block_reason, Some(BlockReason::Saftey)));
+        assert!(feedback.safety_ratings.is_empty());
+    }
+
+    #[test]
+    fn presets() {
+        let config = GenerationConfig::deterministic();
+        assert_eq!(config.temperature, Some(0.0));
+        assert_eq!(config.top_k, Some(1));
+        assert_eq!(config.top_p, None);
+
+        let config = GenerationConfig::balanced();
+        assert_eq!(config.temperature, Some(0.7));
+        assert_eq!(config.top_p, Some(0.95));
+        assert_eq!(config.top_k, Some(40));
+
+        let config = GenerationConfig::creative();
+        assert_eq!(config.temperature, Some(1.3));
+        assert_eq!(config.top_p, Some(0.98));
+        assert_eq!(config.top_k, Some(64));
+    }
+
+    #[test]
+    fn both_response_schemas_is_an_error() {
+        let config = GenerationConfig {
+            response_schema: Some(Schema::new(Type::String)),
+            response_json_schema: Some(serde_json::json!({ "type": "string" })),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn logprobs_serializes() {
+        let config = GenerationConfig {
+            response_logprobs: Some(true),
+            logprobs: Some(5),
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "responseLogprobs": true, "logprobs": 5 })
+        );
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn logprobs_is_validated() {
+        let out_of_range = GenerationConfig {
+            response_logprobs: Some(true),
+            logprobs: Some(21),
+            ..Default::default()
+        };
+        assert!(out_of_range.validate().is_err());
+
+        let without_response_logprobs = GenerationConfig {
+            logprobs: Some(3),
+            ..Default::default()
+        };
+        assert!(without_response_logprobs.validate().is_err());
+    }
+
+    #[test]
+    fn top_k_must_be_positive() {
+        let config = GenerationConfig {
+            top_k: Some(0),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(GenerationConfig::deterministic().validate().is_ok());
+        let json = serde_json::to_value(GenerationConfig::default()).unwrap();
+        assert!(json.get("topK").is_none());
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn embedding_to_array1() {
+        let embedding = ContentEmbedding {
+            values: vec![0.25, -1.0, 3.5],
+        };
+        let array = embedding.to_array1();
+        assert_eq!(array.len(), 3);
+        assert_eq!(array.to_vec(), embedding.values);
+        assert_eq!(array.dot(&array), embedding.into_array1().dot(&array));
+    }
+
+    #[test]
+    fn safety_settings_dedup_by_category() {
+        let mut settings = SafetySettings::new();
+        settings
+            .set(HarmCategory::Harassment, HarmBlockThreshold::Low)
+            .set(HarmCategory::HateSpeech, HarmBlockThreshold::High)
+            .set(HarmCategory::Harassment, HarmBlockThreshold::None);
+
+        let settings = serde_json::to_value(settings.as_slice()).unwrap();
+        assert_eq!(
+            settings,
+            serde_json::json!([
+                { "category": "HARM_CATEGORY_HARASSMENT", "threshold": "BLOCK_NONE" },
+                { "category": "HARM_CATEGORY_HATE_SPEECH", "threshold": "BLOCK_ONLY_HIGH" }
+            ])
+        );
+    }
+
+    #[test]
+    fn safety_ratings_as_map() {
+        let candidate: Candidate = serde_json::from_value(serde_json::json!({
+            "content": { "role": "model", "parts": [{ "text": "hi" }] },
+            "safetyRatings": [
+                { "category": "HARM_CATEGORY_HARASSMENT", "probability": "NEGLIGIBLE" },
+                { "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "probability": "MEDIUM" }
+            ]
+        }))
+        .unwrap();
+
+        let ratings = candidate.safety_ratings_map();
+        assert_eq!(ratings.len(), 2);
+        assert_eq!(
+            ratings[&HarmCategory::DangerousContent],
+            HarmProbability::Medium
+        );
+        assert_eq!(
+            ratings[&HarmCategory::Harassment],
+            HarmProbability::Negligible
+        );
+        assert!(!ratings.contains_key(&HarmCategory::HateSpeech));
+    }
+
+    #[test]
+    fn text_opt_tells_empty_parts_from_empty_text() {
+        let candidate = |parts: serde_json::Value| -> Candidate {
+            serde_json::from_value(serde_json::json!({
+                "content": { "role": "model", "parts": parts }
+            }))
+            .unwrap()
+        };
+
+        let without_parts = candidate(serde_json::json!([]));
+        assert_eq!(without_parts.text_opt(), None);
+        assert_eq!(without_parts.text(), None);
+
+        let empty_text = candidate(serde_json::json!([{ "text": "" }]));
+        assert_eq!(empty_text.text_opt().as_deref(), Some(""));
+        assert_eq!(empty_text.text(), None);
+
+        let blocked: Candidate = serde_json::from_value(serde_json::json!({
+            "content": { "role": "model" },
+            "finishReason": "SAFETY"
+        }))
+        .unwrap();
+        assert_eq!(blocked.text_opt(), None);
+    }
 }
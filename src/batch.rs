@@ -0,0 +1,290 @@
+//! The batch API, which runs large jobs offline, within a day and at a lower cost
+//! than sending each request on its own.
+//!
+//! Create a job with [create_batch], poll it with [get_batch_status] until it is
+//! [done](BatchJob::is_done), then fetch its responses with [get_batch_results].
+
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::GeminiGenericError,
+    error::GeminiError,
+    model::{parse_response, GenerativeModel},
+    GeminiRequest, GeminiResponse,
+};
+
+/// The state of a [BatchJob].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum BatchState {
+    #[serde(rename = "BATCH_STATE_PENDING")]
+    Pending,
+    #[serde(rename = "BATCH_STATE_RUNNING")]
+    Running,
+    #[serde(rename = "BATCH_STATE_SUCCEEDED")]
+    Succeeded,
+    #[serde(rename = "BATCH_STATE_FAILED")]
+    Failed,
+    #[serde(rename = "BATCH_STATE_CANCELLED")]
+    Cancelled,
+    #[serde(rename = "BATCH_STATE_EXPIRED")]
+    Expired,
+    #[serde(other)]
+    Unspecified,
+}
+
+/// A batch job, as returned when creating or polling it.
+#[derive(Debug, Clone)]
+pub struct BatchJob {
+    /// The name identifying the job, e.g. `batches/123`.
+    pub name: String,
+    pub state: BatchState,
+    /// Whether the job has finished, successfully or not.
+    pub done: bool,
+}
+
+impl BatchJob {
+    /// Returns whether the job has finished, successfully or not.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+/// The long-running operation the API returns for a batch job.
+#[derive(Debug, Deserialize)]
+struct Operation {
+    name: String,
+    #[serde(default)]
+    metadata: Option<OperationMetadata>,
+    #[serde(default)]
+    done: bool,
+    error: Option<GeminiGenericError>,
+    response: Option<BatchOutput>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OperationMetadata {
+    state: BatchState,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchOutput {
+    inlined_responses: Option<InlinedResponses>,
+    responses_file: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InlinedResponses {
+    #[serde(default)]
+    inlined_responses: Vec<InlinedResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InlinedResponse {
+    response: Option<GeminiResponse>,
+    error: Option<GeminiGenericError>,
+}
+
+impl From<Operation> for BatchJob {
+    fn from(operation: Operation) -> Self {
+        BatchJob {
+            name: operation.name,
+            state: operation
+                .metadata
+                .map_or(BatchState::Unspecified, |metadata| metadata.state),
+            done: operation.done,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CreateBatchRequest<'a> {
+    batch: Batch<'a>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Batch<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<&'a str>,
+    input_config: InputConfig,
+}
+
+#[derive(Debug, Serialize)]
+struct InputConfig {
+    requests: BatchRequests,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchRequests {
+    requests: Vec<BatchRequest>,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchRequest {
+    request: GeminiRequest,
+}
+
+/// Starts a batch job generating a response to each of `requests` with `model`.
+///
+/// `display_name` is an optional label for the job, to tell jobs apart when listing them.
+pub async fn create_batch(
+    model: &GenerativeModel,
+    display_name: Option<&str>,
+    requests: Vec<GeminiRequest>,
+) -> Result<BatchJob, GeminiError> {
+    let body = CreateBatchRequest {
+        batch: Batch {
+            display_name,
+            input_config: InputConfig {
+                requests: BatchRequests {
+                    requests: requests
+                        .into_iter()
+                        .map(|request| BatchRequest { request })
+                        .collect(),
+                },
+            },
+        },
+    };
+    let url = model.url(&model.model, "batchGenerateContent");
+    let text = send(model.authorized(Method::POST, url).json(&body)).await?;
+    Ok(parse_response::<Operation>(&text)?.into())
+}
+
+/// Fetches the current state of the batch job `name` (e.g. `batches/123`).
+pub async fn get_batch_status(
+    model: &GenerativeModel,
+    name: &str,
+) -> Result<BatchJob, GeminiError> {
+    Ok(get_operation(model, name).await?.into())
+}
+
+/// Fetches the results of the finished batch job `name`, one per request in order.
+///
+/// Returns an error if the job hasn't finished, failed as a whole, or wrote its
+/// results to a file rather than returning them inline.
+pub async fn get_batch_results(
+    model: &GenerativeModel,
+    name: &str,
+) -> Result<Vec<Result<GeminiResponse, GeminiError>>, GeminiError> {
+    let operation = get_operation(model, name).await?;
+    if let Some(error) = operation.error {
+        return Err(error.into());
+    }
+    if !operation.done {
+        return Err(GeminiError::message(&format!(
+            "batch {} has not finished yet",
+            operation.name
+        )));
+    }
+    let output = operation
+        .response
+        .ok_or_else(|| GeminiError::message(&format!("batch {} has no results", operation.name)))?;
+    let Some(inlined) = output.inlined_responses else {
+        return Err(GeminiError::message(&format!(
+            "batch {} wrote its results to {}, which isn't supported",
+            operation.name,
+            output.responses_file.as_deref().unwrap_or("a file")
+        )));
+    };
+    Ok(inlined
+        .inlined_responses
+        .into_iter()
+        .map(|inlined| match (inlined.response, inlined.error) {
+            (_, Some(error)) => Err(error.into()),
+            (Some(response), None) => Ok(response),
+            (None, None) => Err(GeminiError::message("batch result has no response")),
+        })
+        .collect())
+}
+
+async fn get_operation(model: &GenerativeModel, name: &str) -> Result<Operation, GeminiError> {
+    let name = name.strip_prefix("batches/").unwrap_or(name);
+    let url = format!("{}/batches/{name}", model.base_url);
+    let text = send(model.authorized(Method::GET, url)).await?;
+    parse_response(&text)
+}
+
+async fn send(request: reqwest::RequestBuilder) -> Result<String, GeminiError> {
+    request
+        .send()
+        .await
+        .map_err(GeminiError::from)?
+        .text()
+        .await
+        .map_err(GeminiError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+
+    fn operation(state: &str, done: bool) -> serde_json::Value {
+        serde_json::json!({
+            "name": "batches/123",
+            "metadata": { "state": state },
+            "done": done
+        })
+    }
+
+    #[tokio::test]
+    async fn create_poll_and_fetch_results() {
+        let mut succeeded = operation("BATCH_STATE_SUCCEEDED", true);
+        succeeded["response"] = serde_json::json!({
+            "inlinedResponses": { "inlinedResponses": [
+                { "response": {
+                    "candidates": [{ "content": { "role": "model", "parts": [{ "text": "one" }] } }],
+                    "usageMetadata": {}
+                } },
+                { "error": { "code": 400, "message": "bad request" } }
+            ] }
+        });
+        let server = MockServer::start(vec![
+            (200, operation("BATCH_STATE_PENDING", false).to_string()),
+            (200, operation("BATCH_STATE_RUNNING", false).to_string()),
+            (200, succeeded.to_string()),
+        ])
+        .await;
+        let model = server.model().build();
+        let request = |text: &str| GeminiRequest {
+            contents: vec![text.into()],
+            tools: None,
+            safety_settings: None,
+            system_instruction: None,
+            generation_config: None,
+            labels: None,
+        };
+
+        let job = create_batch(
+            &model,
+            Some("nightly eval"),
+            vec![request("a"), request("b")],
+        )
+        .await
+        .unwrap();
+        assert_eq!(job.name, "batches/123");
+        assert_eq!(job.state, BatchState::Pending);
+
+        let job = get_batch_status(&model, &job.name).await.unwrap();
+        assert_eq!(job.state, BatchState::Running);
+        assert!(!job.is_done());
+        let job = get_batch_status(&model, &job.name).await.unwrap();
+        assert_eq!(job.state, BatchState::Succeeded);
+        assert!(job.is_done());
+
+        let results = get_batch_results(&model, &job.name).await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap().text().as_deref(), Some("one"));
+        assert_eq!(results[1].as_ref().unwrap_err().message, "bad request");
+
+        let requests = server.requests();
+        assert!(requests[0].path.ends_with(":batchGenerateContent"));
+        assert_eq!(requests[0].json()["batch"]["displayName"], "nightly eval");
+        let sent = &requests[0].json()["batch"]["inputConfig"]["requests"]["requests"];
+        assert_eq!(sent[1]["request"]["contents"][0]["parts"][0]["text"], "b");
+        assert!(requests[1].path.ends_with("/batches/123"));
+    }
+}
@@ -0,0 +1,101 @@
+//! Blocking versions of the async API, for scripts and CLIs that don't use async.
+//!
+//! Enabled by the `blocking` feature. Calls run on a background Tokio runtime with one
+//! worker thread, started on first use and shared by every model, so connections are
+//! reused between calls. These methods must not be called from within an async
+//! context (e.g. inside a Tokio task), where blocking would stall the executor; Tokio
+//! panics if they are.
+
+use std::{future::Future, sync::OnceLock};
+
+use tokio::runtime::Runtime;
+
+use crate::{
+    chat::ChatSession,
+    content::Content,
+    error::GeminiError,
+    model::{GenerativeModel, GenerativeModelBuilder},
+    EmbedContentConfig, EmbedContentResponse, GeminiResponse,
+};
+
+/// Runs `future` to completion on the shared runtime.
+fn block_on<F: Future>(future: F) -> F::Output {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME
+        .get_or_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .enable_all()
+                .build()
+                .expect("failed to start the runtime for blocking calls")
+        })
+        .block_on(future)
+}
+
+impl GenerativeModel {
+    /// Blocking version of [GenerativeModel::generate_content].
+    pub fn generate_content_blocking(
+        &self,
+        prompt: Vec<Content>,
+    ) -> Result<GeminiResponse, GeminiError> {
+        block_on(self.generate_content(prompt))
+    }
+
+    /// Blocking version of [GenerativeModel::generate_content_with].
+    pub fn generate_content_with_blocking(
+        &self,
+        prompt: Vec<Content>,
+        config: GenerativeModelBuilder,
+    ) -> Result<GeminiResponse, GeminiError> {
+        block_on(self.generate_content_with(prompt, config))
+    }
+
+    /// Blocking version of [GenerativeModel::embed_content].
+    pub fn embed_content_blocking(
+        &self,
+        content: impl Into<Content>,
+        config: EmbedContentConfig,
+    ) -> Result<EmbedContentResponse, GeminiError> {
+        block_on(self.embed_content(content, config))
+    }
+}
+
+impl ChatSession {
+    /// Blocking version of [ChatSession::send_message].
+    pub fn send_message_blocking(
+        &mut self,
+        content: Content,
+    ) -> Result<GeminiResponse, GeminiError> {
+        block_on(self.send_message(content))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+
+    #[test]
+    fn blocking_matches_async() {
+        let runtime = Runtime::new().unwrap();
+        let server = runtime.block_on(MockServer::ok(serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": "Hi!" }] } }],
+            "usageMetadata": { "totalTokenCount": 3 }
+        })));
+        let model = server.model().build();
+
+        let blocking = model
+            .generate_content_blocking(vec![Content::user("Hello")])
+            .unwrap();
+        let asynchronous = runtime
+            .block_on(model.generate_content(vec![Content::user("Hello")]))
+            .unwrap();
+        assert_eq!(blocking.text(), asynchronous.text());
+        assert_eq!(blocking.usage_metadata.total_tokens(), 3);
+
+        let mut chat = model.start_chat(vec![]);
+        chat.send_message_blocking(Content::user("Hello")).unwrap();
+        assert_eq!(chat.history().len(), 2);
+        assert_eq!(server.requests().len(), 3);
+    }
+}
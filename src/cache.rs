@@ -0,0 +1,178 @@
+//! An in-memory cache of responses, to avoid paying twice for identical requests.
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+};
+
+use crate::{model::GeminiModel, GeminiRequest, GeminiResponse};
+
+/// A least-recently-used cache of responses, keyed by a hash of the model and request.
+///
+/// By default only deterministic requests, those with a temperature of 0, are cached,
+/// since other requests are expected to give a different response each time.
+/// Set a cache on a model with
+/// [GenerativeModelBuilder::response_cache](crate::model::GenerativeModelBuilder::response_cache).
+#[derive(Debug)]
+pub struct ResponseCache {
+    capacity: usize,
+    cache_all: bool,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    responses: HashMap<u64, GeminiResponse>,
+    /// The keys of `responses`, least recently used first.
+    order: VecDeque<u64>,
+}
+
+impl ResponseCache {
+    /// Creates a cache holding up to `capacity` responses.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            cache_all: false,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Sets whether requests that aren't deterministic are cached too.
+    pub fn cache_all(mut self, enabled: bool) -> Self {
+        self.cache_all = enabled;
+        self
+    }
+
+    /// The number of cached responses.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().responses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every cached response.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.responses.clear();
+        entries.order.clear();
+    }
+
+    /// The key `request` to `model` is cached under, or `None` if it shouldn't be cached.
+    pub(crate) fn key(&self, model: &GeminiModel, request: &GeminiRequest) -> Option<u64> {
+        let deterministic = request
+            .generation_config
+            .as_ref()
+            .is_some_and(|config| config.temperature == Some(0.0));
+        if !deterministic && !self.cache_all {
+            return None;
+        }
+        // The labels are a `HashMap`, whose order varies between equal maps, so they
+        // are hashed sorted instead.
+        let mut json = serde_json::to_value(request).ok()?;
+        if let Some(object) = json.as_object_mut() {
+            object.remove("labels");
+        }
+        let labels: Option<BTreeMap<_, _>> = request
+            .labels
+            .as_ref()
+            .map(|labels| labels.iter().collect());
+        let mut hasher = DefaultHasher::new();
+        model.hash(&mut hasher);
+        json.to_string().hash(&mut hasher);
+        labels.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    pub(crate) fn get(&self, key: u64) -> Option<GeminiResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let response = entries.responses.get(&key).cloned()?;
+        entries.touch(key);
+        Some(response)
+    }
+
+    pub(crate) fn insert(&self, key: u64, response: GeminiResponse) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.responses.insert(key, response).is_some() {
+            entries.touch(key);
+            return;
+        }
+        entries.order.push_back(key);
+        if entries.order.len() > self.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.responses.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl Entries {
+    /// Marks `key` as the most recently used.
+    fn touch(&mut self, key: u64) {
+        if let Some(position) = self.order.iter().position(|&k| k == key) {
+            self.order.remove(position);
+        }
+        self.order.push_back(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(text: &str) -> GeminiResponse {
+        serde_json::from_value(serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": text }] } }],
+            "usageMetadata": {}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let cache = ResponseCache::new(2);
+        cache.insert(1, response("one"));
+        cache.insert(2, response("two"));
+        assert!(cache.get(1).is_some());
+        cache.insert(3, response("three"));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.get(1).unwrap().text().as_deref(), Some("one"));
+        assert_eq!(cache.get(3).unwrap().text().as_deref(), Some("three"));
+    }
+
+    #[test]
+    fn key_ignores_label_order() {
+        let request = |labels: HashMap<String, String>| GeminiRequest {
+            contents: vec!["hi".into()],
+            tools: None,
+            safety_settings: None,
+            system_instruction: None,
+            generation_config: None,
+            labels: Some(labels),
+        };
+        let labels: Vec<(String, String)> = (0..20)
+            .map(|i| (format!("key{i}"), format!("value{i}")))
+            .collect();
+        let forward = labels.iter().cloned().collect();
+        let backward = labels.iter().rev().cloned().collect();
+        let mut other = labels.clone();
+        other[0].1 = "changed".to_string();
+
+        let cache = ResponseCache::new(1).cache_all(true);
+        let model = GeminiModel::Flash_1_5;
+        let key = cache.key(&model, &request(forward));
+        assert!(key.is_some());
+        assert_eq!(key, cache.key(&model, &request(backward)));
+        assert_ne!(
+            key,
+            cache.key(&model, &request(other.into_iter().collect()))
+        );
+    }
+}
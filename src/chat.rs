@@ -1,22 +1,646 @@
-use crate::{content::Content, error::GeminiError, model::GenerativeModel, GeminiResponse};
+use std::sync::Arc;
+
+use crate::{
+    api::{Candidate, GenerationConfig},
+    content::{Content, Part, Role},
+    error::GeminiError,
+    grounding::GroundingMetadata,
+    model::{GenerativeModel, GenerativeModelBuilder},
+    GeminiResponse,
+};
+
+/// Returns the index of the candidate to pick, if any.
+pub type SelectFn = Arc<dyn Fn(&[Candidate]) -> Option<usize> + Send + Sync>;
+
+/// How a [ChatSession] picks the candidate recorded in its history when a response
+/// has several (see [GenerativeModelBuilder::candidate_count]).
+#[derive(Clone, Default)]
+pub enum CandidateSelector {
+    /// The first candidate.
+    #[default]
+    First,
+    /// The candidate with the highest average log probability, or the first one if
+    /// none has logprobs; see [GeminiResponse::ranked_candidates].
+    HighestLogprob,
+    /// The candidate at the index returned by the closure. If it returns `None` or an
+    /// index out of range, the reply is not recorded.
+    Custom(SelectFn),
+}
+
+impl CandidateSelector {
+    /// Creates a [CandidateSelector::Custom] selector from `select`.
+    pub fn custom(select: impl Fn(&[Candidate]) -> Option<usize> + Send + Sync + 'static) -> Self {
+        CandidateSelector::Custom(Arc::new(select))
+    }
+
+    /// Returns the candidate of `response` to record.
+    fn select<'a>(&self, response: &'a GeminiResponse) -> Option<&'a Candidate> {
+        match self {
+            CandidateSelector::First => response.candidates.first(),
+            CandidateSelector::HighestLogprob => response.ranked_candidates().first().copied(),
+            CandidateSelector::Custom(select) => {
+                select(&response.candidates).and_then(|index| response.candidates.get(index))
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for CandidateSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CandidateSelector::First => f.write_str("First"),
+            CandidateSelector::HighestLogprob => f.write_str("HighestLogprob"),
+            CandidateSelector::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ChatSession {
     pub(crate) model: GenerativeModel,
     pub(crate) history: Vec<Content>,
+    /// The grounding metadata of model turns that had any, with their index in `history`.
+    pub(crate) grounding: Vec<(usize, GroundingMetadata)>,
+    pub(crate) selector: CandidateSelector,
 }
 
 impl ChatSession {
+    /// Sends `content` with the history so far and records both it and the reply.
+    ///
+    /// The history is only updated once a reply arrives, so it is left unchanged if
+    /// sending fails, the response has no candidate, or the future is dropped before
+    /// completing (e.g. by a timeout in `tokio::select!`). When the response has several
+    /// candidates, the one recorded is picked by the session's [CandidateSelector].
     pub async fn send_message(&mut self, content: Content) -> Result<GeminiResponse, GeminiError> {
-        self.history.push(content);
-        let response = self.model.generate_content(self.history.clone()).await;
-        if let Ok(ref response) = response {
-            self.history.push(response.candidates[0].content.clone());
+        let mut contents = self.history.clone();
+        contents.push(content);
+        validate_turns(&contents)?;
+
+        let response = self.model.generate_content(contents.clone()).await?;
+        if let Some(candidate) = self.selector.select(&response) {
+            if let Some(grounding) = &candidate.grounding_metadata {
+                self.grounding.push((contents.len(), grounding.clone()));
+            }
+            contents.push(candidate.content.clone());
+            self.history = contents;
         }
-        response
+        Ok(response)
+    }
+
+    /// Replaces the last model turn with a new reply to the same history, generated with
+    /// `config` instead of the model's generation config, e.g. with a higher temperature
+    /// for a more creative answer.
+    ///
+    /// Returns an `InvalidArgument` error if the history doesn't end with a model turn.
+    /// As with [ChatSession::send_message], the history is only changed once a reply arrives.
+    pub async fn regenerate_with(
+        &mut self,
+        config: GenerationConfig,
+    ) -> Result<GeminiResponse, GeminiError> {
+        let last = match self.history.last() {
+            Some(content) if content.role == Role::Model => self.history.len() - 1,
+            _ => {
+                return Err(GeminiError::invalid_argument(
+                    "the history doesn't end with a model turn to regenerate",
+                ))
+            }
+        };
+
+        let mut overrides = GenerativeModelBuilder::new();
+        overrides.generation_config = Some(config);
+        let response = self
+            .model
+            .generate_content_with(self.history[..last].to_vec(), overrides)
+            .await?;
+        if let Some(candidate) = self.selector.select(&response) {
+            self.grounding.retain(|(index, _)| *index != last);
+            if let Some(grounding) = &candidate.grounding_metadata {
+                self.grounding.push((last, grounding.clone()));
+            }
+            self.history[last] = candidate.content.clone();
+        }
+        Ok(response)
+    }
+
+    /// Sends the results of the model's function calls, each as a separate part of one user turn.
+    pub async fn send_function_responses(
+        &mut self,
+        responses: Vec<(String, serde_json::Value)>,
+    ) -> Result<GeminiResponse, GeminiError> {
+        self.send_message(function_responses(responses)).await
+    }
+
+    /// Replaces all but the most recent turns with a single user turn summarizing them,
+    /// written by the model, to keep long conversations within the context window.
+    ///
+    /// At least `keep_recent` turns are kept; one more is kept if needed for the kept
+    /// turns to start with a model turn, so that the history still alternates. The
+    /// model's system instruction is unaffected. Does nothing if there is nothing to compact.
+    pub async fn summarize_and_compact(&mut self, keep_recent: usize) -> Result<(), GeminiError> {
+        let mut split = self.history.len().saturating_sub(keep_recent);
+        if self
+            .history
+            .get(split)
+            .is_some_and(|content| content.role == Role::User)
+        {
+            split = split.saturating_sub(1);
+        }
+        if split == 0 {
+            return Ok(());
+        }
+
+        let transcript = self.history[..split]
+            .iter()
+            .map(|content| format!("{:?}: {}", content.role, content.text_parts().concat()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let prompt = Content::user(format!("{SUMMARIZE_INSTRUCTION}\n\n{transcript}"));
+        let summary = self
+            .model
+            .generate_content(vec![prompt])
+            .await?
+            .into_text()?;
+
+        let summary = Content::user(format!("{SUMMARY_PREFIX}{summary}"));
+        self.history.splice(..split, [summary]);
+        self.grounding.retain_mut(|(index, _)| {
+            let kept = *index >= split;
+            *index = (*index + 1).saturating_sub(split);
+            kept
+        });
+        Ok(())
+    }
+
+    /// Sets how the candidate recorded in the history is picked when a response has
+    /// several. Defaults to [CandidateSelector::First].
+    pub fn set_candidate_selector(&mut self, selector: CandidateSelector) {
+        self.selector = selector;
+    }
+
+    /// Returns the messages exchanged so far.
+    pub fn history(&self) -> &[Content] {
+        &self.history
+    }
+
+    /// Returns the grounding metadata of the model turn at `index` in the
+    /// [history](ChatSession::history), if that turn was grounded.
+    pub fn grounding(&self, index: usize) -> Option<&GroundingMetadata> {
+        self.grounding
+            .iter()
+            .find(|(turn, _)| *turn == index)
+            .map(|(_, grounding)| grounding)
+    }
+
+    /// Returns the grounding metadata of the most recent grounded model turn, e.g. to
+    /// cite its sources again when answering a follow-up question.
+    pub fn last_grounding(&self) -> Option<&GroundingMetadata> {
+        self.grounding.last().map(|(_, grounding)| grounding)
+    }
+
+    /// Returns the text of each turn with its role, skipping non-text parts
+    /// and turns without any text (e.g. function calls).
+    pub fn transcript(&self) -> Vec<(Role, String)> {
+        self.history
+            .iter()
+            .map(|content| (content.role, content.text_parts().concat()))
+            .filter(|(_, text)| !text.is_empty())
+            .collect()
+    }
+
+    /// Renders the [transcript](ChatSession::transcript) as Markdown, one `**Role:** text` line per turn.
+    pub fn transcript_markdown(&self) -> String {
+        self.transcript()
+            .into_iter()
+            .map(|(role, text)| {
+                let speaker = match role {
+                    Role::User => "User",
+                    Role::Model => "Model",
+                };
+                format!("**{speaker}:** {text}")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Records a user turn in the history without sending it.
+    pub fn push_user_text(&mut self, text: impl Into<Part>) {
+        self.history.push(Content::user(text));
+    }
+
+    /// Records a model turn in the history, e.g. after assembling a streamed response.
+    pub fn push_model_text(&mut self, text: impl Into<Part>) {
+        self.history.push(Content::model(text));
+    }
+
+    /// Checks that the history starts with a user turn and alternates between user and model.
+    ///
+    /// This is called before every message is sent, since the API rejects other histories.
+    pub fn validate_history(&self) -> Result<(), GeminiError> {
+        validate_turns(&self.history)
+    }
+
+    /// Drops the oldest exchanges until the history is estimated to fit in `max_tokens`,
+    /// keeping it starting with a user turn.
+    pub(crate) fn truncate_history(&mut self, max_tokens: usize) {
+        let mut tokens: usize = self.history.iter().map(Content::estimate_tokens).sum();
+        let mut split = 0;
+        while tokens > max_tokens && split < self.history.len() {
+            // Drop a user turn together with the model turn that answered it.
+            for content in self.history.iter().skip(split).take(2) {
+                tokens -= content.estimate_tokens();
+            }
+            split = (split + 2).min(self.history.len());
+        }
+        self.history.drain(..split);
+        self.grounding.retain_mut(|(index, _)| {
+            let kept = *index >= split;
+            *index = index.saturating_sub(split);
+            kept
+        });
     }
     // pub async fn send_message_streamed(&mut self, content: Content) -> GeminiResponse {
     //     self.history.push(content);
     //     self.model.generate_content(self.history.clone()).await
     // }
 }
+
+/// The instruction used by [ChatSession::summarize_and_compact] to summarize old turns.
+const SUMMARIZE_INSTRUCTION: &str = "Summarize the following conversation so that it can \
+    be continued without it. Keep every fact, decision and open question.";
+
+/// Introduces the summary that replaces the compacted turns.
+const SUMMARY_PREFIX: &str = "Summary of the conversation so far: ";
+
+fn validate_turns(history: &[Content]) -> Result<(), GeminiError> {
+    let mut expected = Role::User;
+    for (index, content) in history.iter().enumerate() {
+        if content.role != expected {
+            return Err(GeminiError::invalid_argument(&format!(
+                "history[{index}] has role {:?} but {expected:?} was expected; \
+                 history must start with a user turn and alternate between user and model",
+                content.role
+            )));
+        }
+        expected = match expected {
+            Role::User => Role::Model,
+            Role::Model => Role::User,
+        };
+    }
+    Ok(())
+}
+
+pub(crate) fn function_responses(responses: Vec<(String, serde_json::Value)>) -> Content {
+    Content {
+        role: Role::User,
+        parts: responses
+            .into_iter()
+            .map(|(name, response)| Part::FunctionResponse { name, response })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    use crate::{error::GeminiErrorKind, grounding::GroundingChunk, mock::MockServer};
+
+    fn model() -> GenerativeModel {
+        GenerativeModelBuilder::new().api_key("key").build()
+    }
+
+    #[test]
+    fn valid_history() {
+        let chat = model().start_chat(vec![
+            Content::user("hi"),
+            Content::model("hello"),
+            Content::user("how are you?"),
+        ]);
+        assert!(chat.validate_history().is_ok());
+    }
+
+    #[test]
+    fn invalid_history() {
+        let chat = model().start_chat(vec![Content::user("hi"), Content::user("anyone there?")]);
+        let err = chat.validate_history().unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::InvalidArgument));
+        assert!(err.message.contains("history[1]"));
+
+        let chat = model().start_chat(vec![Content::model("hello")]);
+        assert!(chat
+            .validate_history()
+            .unwrap_err()
+            .message
+            .contains("history[0]"));
+    }
+
+    #[tokio::test]
+    async fn failed_or_cancelled_send_leaves_history_unchanged() {
+        let server = MockServer::start(vec![
+            (
+                500,
+                r#"{"error": {"code": 500, "message": "oops", "status": "INTERNAL"}}"#.to_string(),
+            ),
+            (
+                200,
+                serde_json::json!({
+                    "candidates": [{ "content": { "role": "model", "parts": [{ "text": "hello" }] } }],
+                    "usageMetadata": {}
+                })
+                .to_string(),
+            ),
+        ])
+        .await;
+        let mut chat = server.model().build().start_chat(vec![]);
+
+        assert!(chat.send_message(Content::user("hi")).await.is_err());
+        assert!(chat.history().is_empty());
+
+        // Dropping the future before the response arrives must not leave a dangling turn.
+        assert!(chat
+            .send_message(Content::user("hi"))
+            .now_or_never()
+            .is_none());
+        assert!(chat.history().is_empty());
+
+        chat.send_message(Content::user("hi")).await.unwrap();
+        assert_eq!(chat.transcript().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn grounding_is_kept_per_turn() {
+        let reply = |text: &str, grounding: Option<serde_json::Value>| {
+            let mut candidate = serde_json::json!({
+                "content": { "role": "model", "parts": [{ "text": text }] }
+            });
+            if let Some(grounding) = grounding {
+                candidate["groundingMetadata"] = grounding;
+            }
+            (
+                200,
+                serde_json::json!({ "candidates": [candidate], "usageMetadata": {} }).to_string(),
+            )
+        };
+        let server = MockServer::start(vec![
+            reply(
+                "Spain won Euro 2024.",
+                Some(serde_json::json!({
+                    "groundingChunks": [{ "web": { "uri": "https://uefa.com", "title": "uefa.com" } }],
+                    "webSearchQueries": ["euro 2024 winner"]
+                })),
+            ),
+            reply("They beat England 2-1.", None),
+        ])
+        .await;
+        let mut chat = server.model().build().start_chat(vec![]);
+
+        chat.send_message(Content::user("Who won Euro 2024?"))
+            .await
+            .unwrap();
+        chat.send_message(Content::user("Against whom?"))
+            .await
+            .unwrap();
+
+        let grounding = chat.last_grounding().unwrap();
+        assert!(matches!(
+            &grounding.grounding_chunks[0],
+            GroundingChunk::Web { uri, .. } if uri == "https://uefa.com"
+        ));
+        assert_eq!(grounding.web_search_queries, ["euro 2024 winner"]);
+        assert!(chat.grounding(1).is_some());
+        assert!(chat.grounding(3).is_none());
+    }
+
+    #[tokio::test]
+    async fn compaction_replaces_old_turns_with_summary() {
+        let server = MockServer::ok(serde_json::json!({
+            "candidates": [{
+                "content": { "role": "model", "parts": [{ "text": "We planned a trip to Rome." }] },
+                "finishReason": "STOP"
+            }],
+            "usageMetadata": {}
+        }))
+        .await;
+        let mut chat = server.model().build().start_chat(vec![
+            Content::user("Let's plan a trip."),
+            Content::model("Where to?"),
+            Content::user("Rome."),
+            Content::model("Great choice."),
+            Content::user("When should we go?"),
+            Content::model("Spring is best."),
+        ]);
+
+        chat.summarize_and_compact(2).await.unwrap();
+
+        let transcript = chat.transcript();
+        assert_eq!(transcript.len(), 4);
+        assert_eq!(transcript[0].0, Role::User);
+        assert!(transcript[0].1.ends_with("We planned a trip to Rome."));
+        assert_eq!(transcript[1].1, "Great choice.");
+        assert_eq!(transcript[3].1, "Spring is best.");
+        assert!(chat.validate_history().is_ok());
+
+        let prompt = &server.requests()[0].json()["contents"];
+        assert_eq!(prompt.as_array().unwrap().len(), 1);
+        assert!(prompt[0]["parts"][0]["text"]
+            .as_str()
+            .unwrap()
+            .contains("User: Rome."));
+
+        // Nothing left to compact.
+        chat.summarize_and_compact(4).await.unwrap();
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn push_text_records_roles() {
+        let mut chat = model().start_chat(vec![]);
+        chat.push_user_text("hi");
+        chat.push_model_text(String::from("hello"));
+
+        let roles: Vec<_> = chat.history().iter().map(|content| content.role).collect();
+        assert_eq!(roles, [Role::User, Role::Model]);
+        assert!(chat.validate_history().is_ok());
+    }
+
+    #[test]
+    fn parallel_function_calls() {
+        let response: GeminiResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [{
+                "content": {
+                    "role": "model",
+                    "parts": [
+                        { "functionCall": { "name": "weather", "args": { "city": "Cairo" } } },
+                        { "functionCall": { "name": "weather", "args": { "city": "Paris" } } }
+                    ]
+                }
+            }],
+            "usageMetadata": {}
+        }))
+        .unwrap();
+
+        let calls = response.candidates[0].function_calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].1.unwrap()["city"], "Cairo");
+        assert_eq!(calls[1].1.unwrap()["city"], "Paris");
+
+        let content = function_responses(
+            calls
+                .iter()
+                .map(|(name, args)| {
+                    let city = args.unwrap()["city"].clone();
+                    (
+                        name.to_string(),
+                        serde_json::json!({ "city": city, "temp": 20 }),
+                    )
+                })
+                .collect(),
+        );
+        assert_eq!(content.role, Role::User);
+        assert_eq!(content.parts.len(), 2);
+        assert!(matches!(
+            &content.parts[1],
+            Part::FunctionResponse { name, response } if name == "weather" && response["city"] == "Paris"
+        ));
+    }
+
+    #[tokio::test]
+    async fn thought_signature_is_sent_back_with_the_call() {
+        let call = serde_json::json!({
+            "candidates": [{
+                "content": {
+                    "role": "model",
+                    "parts": [{
+                        "functionCall": { "name": "weather", "args": { "city": "Cairo" } },
+                        "thoughtSignature": "c2lnbmF0dXJl"
+                    }]
+                }
+            }],
+            "usageMetadata": {}
+        });
+        let server = MockServer::ok(call).await;
+        let mut chat = server.model().build().start_chat(vec![]);
+
+        let response = chat
+            .send_message(Content::user("Weather in Cairo?"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            &response.candidates[0].content.parts[0],
+            Part::FunctionCall { thought_signature: Some(signature), .. } if signature == "c2lnbmF0dXJl"
+        ));
+        chat.send_function_responses(vec![(
+            "weather".to_string(),
+            serde_json::json!({ "temp": 30 }),
+        )])
+        .await
+        .unwrap();
+
+        let contents = &server.requests()[1].json()["contents"];
+        assert_eq!(contents[1]["parts"][0]["thoughtSignature"], "c2lnbmF0dXJl");
+        assert_eq!(contents[1]["parts"][0]["functionCall"]["name"], "weather");
+        assert_eq!(
+            contents[2]["parts"][0]["functionResponse"]["response"]["temp"],
+            30
+        );
+    }
+
+    #[tokio::test]
+    async fn regenerate_with_uses_the_given_config() {
+        let server = MockServer::ok(serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": "A dragon's hoard of socks." }] } }],
+            "usageMetadata": {}
+        }))
+        .await;
+        let mut chat = server.model().build().start_chat(vec![
+            Content::user("Name a story."),
+            Content::model("A quiet day."),
+        ]);
+
+        chat.regenerate_with(GenerationConfig {
+            temperature: Some(1.5),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let request = server.requests()[0].json();
+        assert_eq!(request["generation_config"]["temperature"], 1.5);
+        assert_eq!(request["contents"].as_array().unwrap().len(), 1);
+        assert_eq!(chat.history().len(), 2);
+        assert_eq!(
+            chat.history()[1].text_parts(),
+            vec!["A dragon's hoard of socks."]
+        );
+
+        chat.push_user_text("Another?");
+        let err = chat
+            .regenerate_with(GenerationConfig::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::InvalidArgument));
+    }
+
+    #[test]
+    fn transcript_markdown() {
+        let mut chat = model().start_chat(vec![]);
+        chat.push_user_text("What is Rust?");
+        chat.history.push(Content::model(Part::FunctionCall {
+            name: "search".to_string(),
+            args: None,
+            thought_signature: None,
+        }));
+        chat.history.push(function_responses(vec![(
+            "search".to_string(),
+            serde_json::json!({}),
+        )]));
+        chat.push_model_text("A programming language.");
+
+        assert_eq!(
+            chat.transcript(),
+            [
+                (Role::User, "What is Rust?".to_string()),
+                (Role::Model, "A programming language.".to_string())
+            ]
+        );
+        assert_eq!(
+            chat.transcript_markdown(),
+            "**User:** What is Rust?\n**Model:** A programming language."
+        );
+    }
+
+    #[tokio::test]
+    async fn custom_selector_picks_the_recorded_candidate() {
+        let candidate = |text: &str, avg_logprobs: f64| {
+            serde_json::json!({
+                "content": { "role": "model", "parts": [{ "text": text }] },
+                "avgLogprobs": avg_logprobs
+            })
+        };
+        let server = MockServer::ok(serde_json::json!({
+            "candidates": [
+                candidate("Hi.", -0.4),
+                candidate("Hello there, how can I help?", -0.9),
+                candidate("Hey!", -0.1),
+            ],
+            "usageMetadata": {}
+        }))
+        .await;
+        let mut chat = server.model().candidate_count(3).build().start_chat(vec![]);
+
+        chat.set_candidate_selector(CandidateSelector::custom(|candidates| {
+            (0..candidates.len())
+                .max_by_key(|&index| candidates[index].text().map_or(0, |text| text.len()))
+        }));
+        chat.send_message(Content::user("hi")).await.unwrap();
+        assert_eq!(
+            chat.history()[1].text_parts(),
+            ["Hello there, how can I help?"]
+        );
+
+        chat.set_candidate_selector(CandidateSelector::HighestLogprob);
+        chat.send_message(Content::user("again")).await.unwrap();
+        assert_eq!(chat.history()[3].text_parts(), ["Hey!"]);
+    }
+}
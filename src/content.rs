@@ -1,9 +1,23 @@
-use base64::{engine::general_purpose, Engine};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use base64::{
+    alphabet,
+    engine::{general_purpose, DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::error::GeminiError;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Content {
     pub role: Role,
+    /// The parts of the content; the API may omit them, e.g. on a blocked candidate.
+    #[serde(default)]
     pub parts: Vec<Part>,
 }
 
@@ -21,6 +35,183 @@ impl Content {
             parts: vec![value.into()],
         }
     }
+
+    /// Creates a content with one text part per string, e.g. the lines of a stored transcript turn.
+    pub fn from_texts(role: Role, texts: Vec<String>) -> Self {
+        Content {
+            role,
+            parts: texts.into_iter().map(Part::Text).collect(),
+        }
+    }
+
+    /// Creates a user content from a Markdown (or HTML) document, turning each local
+    /// image it references (`![alt](path)` or `<img src="path">`) into an inline data
+    /// part loaded with [Part::from_path], and the text around them into text parts.
+    ///
+    /// Relative image paths are resolved against `base_dir`. Remote images (`http://`,
+    /// `https://` or `data:` URLs) are left in the text. To keep a document from
+    /// uploading arbitrary files, an absolute path or one that resolves outside
+    /// `base_dir` (e.g. through `..` or a symlink) is an `InvalidInput` error.
+    pub fn from_markdown_with_images(md: &str, base_dir: &Path) -> io::Result<Content> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = md;
+        while let Some((start, end, src)) = next_image(rest) {
+            if ["http://", "https://", "data:"]
+                .iter()
+                .any(|scheme| src.starts_with(scheme))
+            {
+                text.push_str(&rest[..end]);
+            } else {
+                text.push_str(&rest[..start]);
+                push_text(&mut parts, &mut text);
+                parts.push(Part::from_path(confined_path(base_dir, src)?)?);
+            }
+            rest = &rest[end..];
+        }
+        text.push_str(rest);
+        push_text(&mut parts, &mut text);
+        Ok(Content {
+            role: Role::User,
+            parts,
+        })
+    }
+
+    /// Appends a part to this content.
+    pub fn push_part(&mut self, part: impl Into<Part>) {
+        self.parts.push(part.into());
+    }
+
+    /// Returns this content with the part appended.
+    pub fn with_part(mut self, part: impl Into<Part>) -> Self {
+        self.push_part(part);
+        self
+    }
+
+    /// Returns the text of every text part, in order.
+    pub fn text_parts(&self) -> Vec<&str> {
+        self.parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Text(text) | Part::TimestampedText { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the `(mime_type, data)` of every inline data part, in order.
+    pub fn data_parts(&self) -> Vec<(&str, &[u8])> {
+        self.parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Data {
+                    data, mime_type, ..
+                } => Some((mime_type.as_str(), data.as_slice())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the `(name, args)` of every function call part, in order.
+    pub fn function_call_parts(&self) -> Vec<(&str, Option<&serde_json::Value>)> {
+        self.parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::FunctionCall { name, args, .. } => Some((name.as_str(), args.as_ref())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Roughly estimates how many tokens this content will use, without calling the API.
+    ///
+    /// Text is counted as one token per 4 characters. Inline data is counted by media
+    /// type: 258 tokens per image or PDF page, 32 per second of audio and 263 per second
+    /// of video, with durations guessed from the size at typical bitrates. This is only
+    /// meant for pre-flight budgeting; it is not billing-accurate.
+    pub fn estimate_tokens(&self) -> usize {
+        self.parts.iter().map(Part::estimate_tokens).sum()
+    }
+
+    /// Splits this content into consecutive contents with the same role, each at most
+    /// `max_tokens` long, splitting text parts that are too long on their own.
+    ///
+    /// `counter` counts the tokens of a piece of text; other parts are counted with
+    /// [Content::estimate_tokens]'s estimates. Content that already fits is returned as is.
+    /// Text is split with [chunk_text], so whitespace at the cuts is dropped. A part that
+    /// can't be split small enough (e.g. inline data) gets a content of its own.
+    ///
+    /// Returns an `InvalidArgument` error if `max_tokens` is zero.
+    pub fn split_if_oversized(
+        self,
+        max_tokens: usize,
+        counter: impl Fn(&str) -> usize,
+    ) -> Result<Vec<Content>, GeminiError> {
+        if max_tokens == 0 {
+            return Err(GeminiError::invalid_argument(
+                "max_tokens must be greater than zero",
+            ));
+        }
+        let count = |part: &Part| match part {
+            Part::Text(text) => counter(text),
+            part => part.estimate_tokens(),
+        };
+        if self.parts.iter().map(count).sum::<usize>() <= max_tokens {
+            return Ok(vec![self]);
+        }
+
+        let role = self.role;
+        let mut contents = Vec::new();
+        let mut parts = Vec::new();
+        let mut used = 0;
+        for part in self.parts {
+            let pieces = match part {
+                Part::Text(text) => split_text(&text, max_tokens, &counter)
+                    .into_iter()
+                    .map(Part::Text)
+                    .collect(),
+                part => vec![part],
+            };
+            for piece in pieces {
+                let tokens = count(&piece);
+                if !parts.is_empty() && used + tokens > max_tokens {
+                    contents.push(Content {
+                        role,
+                        parts: std::mem::take(&mut parts),
+                    });
+                    used = 0;
+                }
+                used += tokens;
+                parts.push(piece);
+            }
+        }
+        if !parts.is_empty() {
+            contents.push(Content { role, parts });
+        }
+        Ok(contents)
+    }
+}
+
+/// Splits `text` into pieces of at most `max_tokens` tokens according to `counter`.
+fn split_text(text: &str, max_tokens: usize, counter: &impl Fn(&str) -> usize) -> Vec<String> {
+    let tokens = counter(text);
+    if tokens <= max_tokens {
+        return vec![text.to_string()];
+    }
+
+    // Aim for pieces of equal length, then split any piece that is still too long.
+    let chars = text.chars().count();
+    let max_chars = (chars / tokens.div_ceil(max_tokens)).max(1);
+    chunk_chars(text, max_chars, 0)
+        .into_iter()
+        .flat_map(|chunk| {
+            if chunk.chars().count() < chars {
+                split_text(&chunk, max_tokens, counter)
+            } else {
+                vec![chunk]
+            }
+        })
+        .collect()
 }
 
 impl<T> From<T> for Content
@@ -33,27 +224,352 @@ where
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(rename_all = "camelCase")]
+#[serde(try_from = "WirePart", into = "WirePart")]
 pub enum Part {
     Text(String),
-    #[serde(rename = "inlineData")]
     Data {
-        #[serde(serialize_with = "ser_data")]
-        #[serde(deserialize_with = "des_data")]
         data: Vec<u8>,
-        #[serde(rename = "mimeType")]
         mime_type: String,
+        /// A label for the data (e.g. `"chart"`), to keep track of which attachment is which.
+        ///
+        /// The API has no such field, so the name is never serialized, and parts
+        /// deserialized from JSON have none.
+        display_name: Option<String>,
     },
     FunctionCall {
         name: String,
         args: Option<serde_json::Value>,
+        /// An opaque signature of the reasoning that led a thinking model to this call.
+        ///
+        /// It must be sent back with the call in later turns for the model to keep its
+        /// train of thought, which happens when the call stays in the chat history.
+        thought_signature: Option<String>,
     },
+    /// The result of a function call, sent back to the model.
+    ///
+    /// By convention `response` holds the result under an `output` key, or a description
+    /// of the failure under an `error` key (see [Part::function_error]).
     FunctionResponse {
         name: String,
         response: serde_json::Value,
     },
+    /// Text covering a span of the input media, e.g. one segment of an audio transcription.
+    TimestampedText {
+        text: String,
+        /// Where the segment starts, from the beginning of the media.
+        start: Duration,
+        /// Where the segment ends, from the beginning of the media.
+        end: Duration,
+    },
+    /// Code the model generated for the code execution tool to run.
+    ExecutableCode {
+        language: Language,
+        code: String,
+    },
+    /// The result of running an [Part::ExecutableCode] part.
+    CodeExecutionResult {
+        outcome: Outcome,
+        /// The standard output on success, or a description of the failure otherwise.
+        output: String,
+    },
 }
 
+/// The programming language of [Part::ExecutableCode].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Language {
+    #[serde(rename = "LANGUAGE_UNSPECIFIED")]
+    Unspecified,
+    Python,
+}
+
+/// How running the code of a [Part::CodeExecutionResult] went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Outcome {
+    #[serde(rename = "OUTCOME_UNSPECIFIED")]
+    Unspecified,
+    #[serde(rename = "OUTCOME_OK")]
+    Ok,
+    #[serde(rename = "OUTCOME_FAILED")]
+    Failed,
+    /// The code ran for too long and was cancelled; the output may be partial.
+    #[serde(rename = "OUTCOME_DEADLINE_EXCEEDED")]
+    DeadlineExceeded,
+}
+
+/// A [Part] as the API represents it: an object with one field per kind of data,
+/// plus fields that apply to several kinds (like the media offsets).
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WirePart {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inline_data: Option<WireBlob>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_call: Option<WireFunctionCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_response: Option<WireFunctionResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    executable_code: Option<WireExecutableCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_execution_result: Option<WireCodeExecutionResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thought_signature: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_offset: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_offset: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WireBlob {
+    #[serde(serialize_with = "ser_data")]
+    #[serde(deserialize_with = "des_data")]
+    data: Vec<u8>,
+    mime_type: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WireFunctionCall {
+    name: String,
+    args: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WireFunctionResponse {
+    name: String,
+    response: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WireExecutableCode {
+    language: Language,
+    code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WireCodeExecutionResult {
+    outcome: Outcome,
+    #[serde(default)]
+    output: String,
+}
+
+impl TryFrom<WirePart> for Part {
+    type Error = String;
+
+    fn try_from(value: WirePart) -> Result<Self, Self::Error> {
+        let part = match value {
+            WirePart {
+                text: Some(text),
+                start_offset: Some(start),
+                end_offset: Some(end),
+                ..
+            } => Part::TimestampedText {
+                text,
+                start: parse_duration(&start)?,
+                end: parse_duration(&end)?,
+            },
+            WirePart {
+                text: Some(text), ..
+            } => Part::Text(text),
+            WirePart {
+                inline_data: Some(WireBlob { data, mime_type }),
+                ..
+            } => Part::Data {
+                data,
+                mime_type,
+                display_name: None,
+            },
+            WirePart {
+                function_call: Some(WireFunctionCall { name, args }),
+                thought_signature,
+                ..
+            } => Part::FunctionCall {
+                name,
+                args,
+                thought_signature,
+            },
+            WirePart {
+                function_response: Some(WireFunctionResponse { name, response }),
+                ..
+            } => Part::FunctionResponse { name, response },
+            WirePart {
+                executable_code: Some(WireExecutableCode { language, code }),
+                ..
+            } => Part::ExecutableCode { language, code },
+            WirePart {
+                code_execution_result: Some(WireCodeExecutionResult { outcome, output }),
+                ..
+            } => Part::CodeExecutionResult { outcome, output },
+            _ => return Err("unsupported part".to_string()),
+        };
+        Ok(part)
+    }
+}
+
+impl From<Part> for WirePart {
+    fn from(value: Part) -> Self {
+        match value {
+            Part::Text(text) => WirePart {
+                text: Some(text),
+                ..Default::default()
+            },
+            Part::Data {
+                data, mime_type, ..
+            } => WirePart {
+                inline_data: Some(WireBlob { data, mime_type }),
+                ..Default::default()
+            },
+            Part::FunctionCall {
+                name,
+                args,
+                thought_signature,
+            } => WirePart {
+                function_call: Some(WireFunctionCall { name, args }),
+                thought_signature,
+                ..Default::default()
+            },
+            Part::FunctionResponse { name, response } => WirePart {
+                function_response: Some(WireFunctionResponse { name, response }),
+                ..Default::default()
+            },
+            Part::ExecutableCode { language, code } => WirePart {
+                executable_code: Some(WireExecutableCode { language, code }),
+                ..Default::default()
+            },
+            Part::CodeExecutionResult { outcome, output } => WirePart {
+                code_execution_result: Some(WireCodeExecutionResult { outcome, output }),
+                ..Default::default()
+            },
+            Part::TimestampedText { text, start, end } => WirePart {
+                text: Some(text),
+                start_offset: Some(format_duration(start)),
+                end_offset: Some(format_duration(end)),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Resolves `src` against `base_dir`, making sure it stays inside it.
+fn confined_path(base_dir: &Path, src: &str) -> io::Result<PathBuf> {
+    let outside = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("image {src} is outside {}", base_dir.display()),
+        )
+    };
+    if Path::new(src).has_root() {
+        return Err(outside());
+    }
+    let path = base_dir.join(src).canonicalize()?;
+    if !path.starts_with(base_dir.canonicalize()?) {
+        return Err(outside());
+    }
+    Ok(path)
+}
+
+/// Moves `text`, unless it is blank, into a text part.
+fn push_text(parts: &mut Vec<Part>, text: &mut String) {
+    let taken = std::mem::take(text);
+    if !taken.trim().is_empty() {
+        parts.push(Part::Text(taken.trim().to_string()));
+    }
+}
+
+/// Finds the first image reference in `text`, returning its byte range and source.
+fn next_image(text: &str) -> Option<(usize, usize, &str)> {
+    match (markdown_image(text), html_image(text)) {
+        (Some(md), Some(html)) => Some(if md.0 <= html.0 { md } else { html }),
+        (md, html) => md.or(html),
+    }
+}
+
+/// Finds the first `![alt](src "title")` in `text`.
+fn markdown_image(text: &str) -> Option<(usize, usize, &str)> {
+    let mut from = 0;
+    while let Some(offset) = text[from..].find("![") {
+        let start = from + offset;
+        from = start + 2;
+        let alt_end = from + text[from..].find(']')?;
+        let Some(target) = text[alt_end + 1..].strip_prefix('(') else {
+            continue;
+        };
+        let Some(target_len) = target.find(')') else {
+            continue;
+        };
+        let src = target[..target_len].split_whitespace().next().unwrap_or("");
+        let src = src.trim_start_matches('<').trim_end_matches('>');
+        if !src.is_empty() {
+            return Some((start, alt_end + 2 + target_len + 1, src));
+        }
+    }
+    None
+}
+
+/// Finds the first `<img src="src">` in `text`.
+fn html_image(text: &str) -> Option<(usize, usize, &str)> {
+    let mut from = 0;
+    while let Some(offset) = text[from..].find("<img") {
+        let start = from + offset;
+        from = start + 4;
+        let end = start + text[start..].find('>')? + 1;
+        let tag = &text[start..end];
+        let Some(attribute) = tag.find("src=").map(|i| &tag[i + 4..]) else {
+            continue;
+        };
+        let Some(quote) = attribute.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            continue;
+        };
+        if let Some(len) = attribute[1..].find(quote) {
+            return Some((start, end, &attribute[1..1 + len]));
+        }
+    }
+    None
+}
+
+/// Returns the MIME type of `data` according to its magic bytes, if recognized.
+fn sniff_mime_type(data: &[u8]) -> Option<&'static str> {
+    let riff_type = data.starts_with(b"RIFF").then(|| data.get(8..12)).flatten();
+    let mime_type = match data {
+        [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => "image/png",
+        [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => "image/gif",
+        [b'%', b'P', b'D', b'F', b'-', ..] => "application/pdf",
+        _ if riff_type == Some(b"WEBP") => "image/webp",
+        _ if riff_type == Some(b"WAVE") => "audio/wav",
+        // An ID3 tag, or an MPEG audio frame header (11 set sync bits).
+        [b'I', b'D', b'3', ..] => "audio/mp3",
+        [0xff, second, ..] if second & 0xe0 == 0xe0 => "audio/mp3",
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => "video/mp4",
+        _ => return None,
+    };
+    Some(mime_type)
+}
+
+/// Parses a protobuf JSON duration such as `"1.5s"`.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    value
+        .strip_suffix('s')
+        .and_then(|seconds| seconds.parse::<f64>().ok())
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("invalid duration: {value}"))
+}
+
+fn format_duration(value: Duration) -> String {
+    format!("{}s", value.as_secs_f64())
+}
+
+/// URL-safe base64, with or without padding.
+const URL_SAFE_LENIENT: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Encodes inline data as standard base64, which is what the API expects.
 fn ser_data<S>(bytes: &Vec<u8>, ser: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -61,14 +577,135 @@ where
     ser.serialize_str(&general_purpose::STANDARD.encode(bytes))
 }
 
+/// Decodes inline data from standard base64, falling back to URL-safe base64
+/// for data that didn't come from the API.
 fn des_data<'de, D>(des: D) -> Result<Vec<u8>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    Ok(general_purpose::STANDARD
-        .decode(String::deserialize(des)?)
-        .unwrap())
+    let encoded = String::deserialize(des)?;
+    general_purpose::STANDARD
+        .decode(&encoded)
+        .or_else(|_| URL_SAFE_LENIENT.decode(&encoded))
+        .map_err(|err| serde::de::Error::custom(format!("invalid base64 inline data: {err}")))
+}
+
+impl Part {
+    /// Creates a function response telling the model that the call to `name` failed.
+    pub fn function_error(name: &str, message: &str) -> Self {
+        Part::FunctionResponse {
+            name: name.to_string(),
+            response: serde_json::json!({ "error": { "message": message } }),
+        }
+    }
+
+    /// Creates an inline data part from the file at `path`, named after the file.
+    ///
+    /// The MIME type is detected like [Part::data_auto], then from the file extension;
+    /// returns an `InvalidData` error if neither is recognized.
+    pub fn from_path(path: impl AsRef<Path>) -> io::Result<Part> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        let mime_type = sniff_mime_type(&data)
+            .or(match extension.as_deref() {
+                Some("png") => Some("image/png"),
+                Some("jpg" | "jpeg") => Some("image/jpeg"),
+                Some("gif") => Some("image/gif"),
+                Some("webp") => Some("image/webp"),
+                Some("heic") => Some("image/heic"),
+                Some("heif") => Some("image/heif"),
+                Some("pdf") => Some("application/pdf"),
+                Some("txt") => Some("text/plain"),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unrecognized file type: {}", path.display()),
+                )
+            })?;
+        Ok(Part::Data {
+            data,
+            mime_type: mime_type.to_string(),
+            display_name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned()),
+        })
+    }
+
+    /// Creates an inline data part, detecting its MIME type from the magic bytes at its start.
+    ///
+    /// Recognizes PNG, JPEG, GIF, WebP, PDF, WAV, MP3 and MP4 data, and returns an
+    /// `InvalidArgument` error for anything else.
+    pub fn data_auto(data: Vec<u8>) -> Result<Part, GeminiError> {
+        let mime_type = sniff_mime_type(&data)
+            .ok_or_else(|| GeminiError::invalid_argument("unrecognized inline data format"))?;
+        Ok(Part::Data {
+            data,
+            mime_type: mime_type.to_string(),
+            display_name: None,
+        })
+    }
+
+    fn estimate_tokens(&self) -> usize {
+        match self {
+            Part::Text(text) | Part::TimestampedText { text, .. } => {
+                text.chars().count().div_ceil(CHARS_PER_TOKEN)
+            }
+            Part::Data {
+                data, mime_type, ..
+            } => estimate_data_tokens(mime_type, data),
+            Part::FunctionCall { .. }
+            | Part::FunctionResponse { .. }
+            | Part::ExecutableCode { .. }
+            | Part::CodeExecutionResult { .. } => serde_json::to_string(self)
+                .map(|json| json.len().div_ceil(CHARS_PER_TOKEN))
+                .unwrap_or_default(),
+        }
+    }
 }
+
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Estimates the tokens of inline data, see [Content::estimate_tokens].
+fn estimate_data_tokens(mime_type: &str, data: &[u8]) -> usize {
+    const TOKENS_PER_IMAGE: usize = 258;
+    const TOKENS_PER_PAGE: usize = 258;
+    const AUDIO_TOKENS_PER_SECOND: usize = 32;
+    const VIDEO_TOKENS_PER_SECOND: usize = 263;
+    // 128 kbps audio and 2 Mbps video.
+    const AUDIO_BYTES_PER_SECOND: usize = 16_000;
+    const VIDEO_BYTES_PER_SECOND: usize = 250_000;
+
+    let seconds = |bytes_per_second: usize| data.len().div_ceil(bytes_per_second).max(1);
+    match mime_type.split('/').next() {
+        _ if mime_type == "application/pdf" => pdf_pages(data).max(1) * TOKENS_PER_PAGE,
+        Some("audio") => seconds(AUDIO_BYTES_PER_SECOND) * AUDIO_TOKENS_PER_SECOND,
+        Some("video") => seconds(VIDEO_BYTES_PER_SECOND) * VIDEO_TOKENS_PER_SECOND,
+        Some("text") => String::from_utf8_lossy(data)
+            .chars()
+            .count()
+            .div_ceil(CHARS_PER_TOKEN),
+        _ => TOKENS_PER_IMAGE,
+    }
+}
+
+/// Counts the page objects (`/Type /Page`, but not `/Type /Pages`) of a PDF.
+fn pdf_pages(data: &[u8]) -> usize {
+    (0..data.len())
+        .filter_map(|i| data[i..].strip_prefix(b"/Type"))
+        .map(|rest| {
+            let start = rest.iter().take_while(|b| b.is_ascii_whitespace()).count();
+            &rest[start..]
+        })
+        .filter(|rest| rest.starts_with(b"/Page") && rest.get(5) != Some(&b's'))
+        .count()
+}
+
 impl From<&str> for Part {
     fn from(value: &str) -> Self {
         Part::Text(value.to_string())
@@ -81,9 +718,528 @@ impl From<String> for Part {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum Role {
     User,
     Model,
 }
+
+/// Builds a few-shot prompt: example exchanges as alternating user and model turns,
+/// followed by the actual query, e.g.
+/// `FewShot::new().example("2+2", "4").query("4+4").build()`.
+#[derive(Debug, Clone, Default)]
+pub struct FewShot {
+    contents: Vec<Content>,
+    query: Option<Content>,
+}
+
+impl FewShot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an example of `input` and the `output` the model should give for it.
+    pub fn example(mut self, input: impl Into<Part>, output: impl Into<Part>) -> Self {
+        self.contents.push(Content::user(input));
+        self.contents.push(Content::model(output));
+        self
+    }
+
+    /// Sets the input the model should answer, replacing any query set before.
+    pub fn query(mut self, input: impl Into<Part>) -> Self {
+        self.query = Some(Content::user(input));
+        self
+    }
+
+    /// Returns the examples followed by the query, ready to send as a prompt.
+    ///
+    /// Without a query the prompt ends with the last example's model turn.
+    pub fn build(self) -> Vec<Content> {
+        let mut contents = self.contents;
+        contents.extend(self.query);
+        contents
+    }
+}
+
+/// Splits `text` into chunks of at most `max_chars` characters, e.g. to embed a long document.
+///
+/// Chunks end at a paragraph break where possible, otherwise at the end of a sentence,
+/// otherwise between words. Each chunk after the first starts with (roughly) the last
+/// `overlap` characters of the previous one, so that context spanning a cut isn't lost.
+///
+/// Returns an `InvalidArgument` error if `max_chars` is zero or `overlap` is not
+/// smaller than `max_chars`.
+pub fn chunk_text(
+    text: &str,
+    max_chars: usize,
+    overlap: usize,
+) -> Result<Vec<String>, GeminiError> {
+    if max_chars == 0 {
+        return Err(GeminiError::invalid_argument(
+            "max_chars must be greater than zero",
+        ));
+    }
+    if overlap >= max_chars {
+        return Err(GeminiError::invalid_argument(
+            "overlap must be smaller than max_chars",
+        ));
+    }
+    Ok(chunk_chars(text, max_chars, overlap))
+}
+
+/// [chunk_text] with arguments already checked.
+fn chunk_chars(text: &str, max_chars: usize, overlap: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < chars.len() {
+        let end = if chars.len() - start <= max_chars {
+            chars.len()
+        } else {
+            find_cut(&chars, start, start + max_chars)
+        };
+        let chunk: String = chars[start..end].iter().collect();
+        let chunk = chunk.trim();
+        if !chunk.is_empty() {
+            chunks.push(chunk.to_string());
+        }
+        if end == chars.len() {
+            break;
+        }
+
+        // Back up by `overlap`, preferring to start the overlap at the beginning of a word.
+        // Always make progress.
+        let back = end.saturating_sub(overlap).max(start + 1);
+        let next = (back..end)
+            .find(|&i| i == 0 || chars[i - 1].is_whitespace())
+            .unwrap_or(back);
+        start = if next > start { next } else { end };
+    }
+    chunks
+}
+
+/// Finds where to end a chunk that starts at `start` and may not extend past `limit`.
+fn find_cut(chars: &[char], start: usize, limit: usize) -> usize {
+    // Don't settle for a boundary that would leave a tiny chunk.
+    let min = start + (limit - start) / 2;
+    let last =
+        |is_boundary: &dyn Fn(usize) -> bool| (min + 1..=limit).rev().find(|&i| is_boundary(i));
+
+    let paragraph = |i: usize| i >= 2 && chars[i - 1] == '\n' && chars[i - 2] == '\n';
+    let sentence = |i: usize| {
+        i >= 2 && chars[i - 1].is_whitespace() && matches!(chars[i - 2], '.' | '!' | '?')
+    };
+    let word = |i: usize| chars[i - 1].is_whitespace();
+
+    last(&paragraph)
+        .or_else(|| last(&sentence))
+        .or_else(|| last(&word))
+        .unwrap_or(limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_parts_in_order() {
+        let mut content = Content::user("first");
+        content.push_part("second");
+        let content = content.with_part("third");
+
+        let texts: Vec<_> = content
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.as_str(),
+                _ => panic!("expected a text part"),
+            })
+            .collect();
+        assert_eq!(texts, ["first", "second", "third"]);
+    }
+
+    #[test]
+    fn filter_parts_by_kind() {
+        let content = Content::model("Here is the chart")
+            .with_part(Part::Data {
+                data: vec![1, 2, 3],
+                mime_type: "image/png".to_string(),
+                display_name: None,
+            })
+            .with_part(Part::FunctionCall {
+                name: "plot".to_string(),
+                args: None,
+                thought_signature: None,
+            })
+            .with_part("and a summary");
+
+        assert_eq!(content.text_parts(), ["Here is the chart", "and a summary"]);
+        assert_eq!(content.data_parts(), [("image/png", &[1u8, 2, 3][..])]);
+        assert_eq!(content.function_call_parts(), [("plot", None)]);
+    }
+
+    #[test]
+    fn timestamped_transcription() {
+        let content: Content = serde_json::from_value(serde_json::json!({
+            "role": "model",
+            "parts": [
+                { "text": "Hello there.", "startOffset": "0s", "endOffset": "1.5s" },
+                { "text": "General Kenobi!", "startOffset": "1.5s", "endOffset": "3.25s" },
+                { "text": "(end of transcript)" }
+            ]
+        }))
+        .unwrap();
+
+        assert!(matches!(
+            &content.parts[1],
+            Part::TimestampedText { text, start, end }
+                if text == "General Kenobi!"
+                    && *start == Duration::from_millis(1500)
+                    && *end == Duration::from_millis(3250)
+        ));
+        assert!(matches!(&content.parts[2], Part::Text(_)));
+
+        let json = serde_json::to_value(&content.parts[1]).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "text": "General Kenobi!", "startOffset": "1.5s", "endOffset": "3.25s" })
+        );
+    }
+
+    #[test]
+    fn wire_format_is_unchanged() {
+        let parts = vec![
+            Part::Text("hi".to_string()),
+            Part::Data {
+                data: vec![1, 2, 3],
+                mime_type: "image/png".to_string(),
+                display_name: None,
+            },
+            Part::FunctionCall {
+                name: "f".to_string(),
+                args: None,
+                thought_signature: None,
+            },
+        ];
+        assert_eq!(
+            serde_json::to_value(&parts).unwrap(),
+            serde_json::json!([
+                { "text": "hi" },
+                { "inlineData": { "data": "AQID", "mimeType": "image/png" } },
+                { "functionCall": { "name": "f", "args": null } }
+            ])
+        );
+    }
+
+    #[test]
+    fn data_estimates_depend_on_media_type() {
+        let data = |mime_type: &str, data: Vec<u8>| Part::Data {
+            data,
+            mime_type: mime_type.to_string(),
+            display_name: None,
+        };
+        let pdf = b"%PDF-1.4 /Type /Pages /Type /Page /Type/Page /Type /Page".to_vec();
+
+        assert_eq!(data("image/png", vec![0; 10]).estimate_tokens(), 258);
+        assert_eq!(data("application/pdf", pdf).estimate_tokens(), 3 * 258);
+        // 10 seconds at 128 kbps
+        assert_eq!(data("audio/mp3", vec![0; 160_000]).estimate_tokens(), 320);
+        assert_eq!(data("video/mp4", vec![0; 500_000]).estimate_tokens(), 526);
+        assert_eq!(data("text/plain", vec![b'a'; 40]).estimate_tokens(), 10);
+    }
+
+    #[test]
+    fn estimate_tokens_is_close_to_reference() {
+        // counted as 45 tokens by the API
+        let content = Content::user(
+            "Rust is a multi-paradigm, general-purpose programming language that emphasizes \
+             performance, type safety, and concurrency. It enforces memory safety without a \
+             garbage collector.",
+        );
+        let ratio = content.estimate_tokens() as f64 / 45.0;
+        assert!((0.5..=2.0).contains(&ratio), "ratio was {ratio}");
+    }
+
+    #[test]
+    fn chunk_text_prefers_paragraphs_then_sentences() {
+        let text = "First paragraph here.\n\nSecond one. It has two sentences.";
+        let chunks = chunk_text(text, 40, 0).unwrap();
+        assert_eq!(
+            chunks,
+            ["First paragraph here.", "Second one. It has two sentences."]
+        );
+
+        let chunks = chunk_text("One sentence. Another sentence. A third one.", 32, 0).unwrap();
+        assert_eq!(chunks, ["One sentence. Another sentence.", "A third one."]);
+    }
+
+    #[test]
+    fn chunk_text_overlap() {
+        let text = "alpha beta gamma delta epsilon zeta eta theta";
+        let chunks = chunk_text(text, 20, 8).unwrap();
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 20));
+        assert_eq!(chunks[0], "alpha beta gamma");
+        // The next chunk repeats the tail of the previous one, starting on a word.
+        assert!(chunks[1].starts_with("gamma"));
+        assert!(chunks.last().unwrap().ends_with("theta"));
+    }
+
+    #[test]
+    fn chunk_text_splits_long_words() {
+        let chunks = chunk_text("abcdefghij", 4, 1).unwrap();
+        assert_eq!(chunks, ["abcd", "defg", "ghij"]);
+        assert!(chunk_text("", 10, 0).unwrap().is_empty());
+        assert_eq!(chunk_text("short", 10, 2).unwrap(), ["short"]);
+    }
+
+    #[test]
+    fn chunk_text_overlap_larger_than_the_cut() {
+        // The first cut falls 6 chars in, before the 9 chars of overlap.
+        let chunks = chunk_text("abcde fghijklmnopqrstuvwxyz", 10, 9).unwrap();
+        assert_eq!(chunks[0], "abcde");
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 10));
+        assert!(chunks.last().unwrap().ends_with("xyz"));
+
+        let err = chunk_text("text", 4, 4).unwrap_err();
+        assert!(matches!(
+            err.kind,
+            crate::error::GeminiErrorKind::InvalidArgument
+        ));
+        assert!(chunk_text("text", 0, 0).is_err());
+    }
+
+    #[test]
+    fn from_texts() {
+        let content = Content::from_texts(
+            Role::Model,
+            vec!["first line".to_string(), "second line".to_string()],
+        );
+        assert_eq!(content.role, Role::Model);
+        assert_eq!(content.text_parts(), ["first line", "second line"]);
+        assert_eq!(content.parts.len(), 2);
+    }
+
+    #[test]
+    fn function_error_shape() {
+        let part = Part::function_error("get_weather", "city not found");
+        assert_eq!(
+            serde_json::to_value(&part).unwrap(),
+            serde_json::json!({
+                "functionResponse": {
+                    "name": "get_weather",
+                    "response": { "error": { "message": "city not found" } }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn oversized_text_is_split_into_contents() {
+        let words = |text: &str| text.split_whitespace().count();
+        let text = "one two three four five six seven eight nine ten";
+        let content = Content::user("intro").with_part(text);
+
+        let contents = content.split_if_oversized(4, words).unwrap();
+        assert!(contents.len() > 1);
+        for content in &contents {
+            assert_eq!(content.role, Role::User);
+            assert!(
+                content
+                    .text_parts()
+                    .iter()
+                    .map(|text| words(text))
+                    .sum::<usize>()
+                    <= 4
+            );
+        }
+        let all: Vec<_> = contents
+            .iter()
+            .flat_map(|content| content.text_parts())
+            .flat_map(str::split_whitespace)
+            .collect();
+        assert_eq!(all.join(" "), format!("intro {text}"));
+
+        let small = Content::user("fits").split_if_oversized(4, words).unwrap();
+        assert_eq!(small.len(), 1);
+
+        let err = Content::user("fits")
+            .split_if_oversized(0, words)
+            .unwrap_err();
+        assert!(matches!(
+            err.kind,
+            crate::error::GeminiErrorKind::InvalidArgument
+        ));
+    }
+
+    #[test]
+    fn code_execution_parts_deserialize() {
+        let content: Content = serde_json::from_value(serde_json::json!({
+            "role": "model",
+            "parts": [
+                { "executableCode": { "language": "PYTHON", "code": "print(1 + 1)" } },
+                { "codeExecutionResult": { "outcome": "OUTCOME_OK", "output": "2\n" } }
+            ]
+        }))
+        .unwrap();
+
+        assert!(matches!(
+            &content.parts[0],
+            Part::ExecutableCode { language: Language::Python, code } if code == "print(1 + 1)"
+        ));
+        assert!(matches!(
+            &content.parts[1],
+            Part::CodeExecutionResult { outcome: Outcome::Ok, output } if output == "2\n"
+        ));
+        assert_eq!(
+            serde_json::to_value(&content.parts[1]).unwrap(),
+            serde_json::json!({ "codeExecutionResult": { "outcome": "OUTCOME_OK", "output": "2\n" } })
+        );
+    }
+
+    #[test]
+    fn inline_data_accepts_url_safe_base64() {
+        // 0xfb 0xff encodes to "+/8=" in standard base64 and "-_8" in unpadded URL-safe base64.
+        let part: Part = serde_json::from_value(serde_json::json!({
+            "inlineData": { "mimeType": "application/octet-stream", "data": "-_8" }
+        }))
+        .unwrap();
+        assert!(matches!(&part, Part::Data { data, .. } if data == &[0xfb, 0xff]));
+        assert_eq!(
+            serde_json::to_value(&part).unwrap()["inlineData"]["data"],
+            "+/8="
+        );
+
+        let invalid = serde_json::from_value::<Part>(serde_json::json!({
+            "inlineData": { "mimeType": "application/octet-stream", "data": "not base64!" }
+        }));
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn few_shot_alternates_roles() {
+        let prompt = FewShot::new()
+            .example("2+2", "4")
+            .example("3+3", "6")
+            .query("4+4")
+            .build();
+
+        let roles: Vec<Role> = prompt.iter().map(|content| content.role).collect();
+        assert_eq!(
+            roles,
+            [Role::User, Role::Model, Role::User, Role::Model, Role::User]
+        );
+        let texts: Vec<&str> = prompt.iter().flat_map(Content::text_parts).collect();
+        assert_eq!(texts, ["2+2", "4", "3+3", "6", "4+4"]);
+    }
+
+    #[test]
+    fn data_auto_detects_mime_type() {
+        let png = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 0x00];
+        assert!(matches!(
+            Part::data_auto(png).unwrap(),
+            Part::Data { mime_type, .. } if mime_type == "image/png"
+        ));
+        let pdf = b"%PDF-1.7\n".to_vec();
+        assert!(matches!(
+            Part::data_auto(pdf).unwrap(),
+            Part::Data { mime_type, .. } if mime_type == "application/pdf"
+        ));
+        let wav = b"RIFF\x24\x00\x00\x00WAVEfmt ".to_vec();
+        assert!(matches!(
+            Part::data_auto(wav).unwrap(),
+            Part::Data { mime_type, .. } if mime_type == "audio/wav"
+        ));
+
+        let err = Part::data_auto(b"plain text".to_vec()).unwrap_err();
+        assert!(matches!(
+            err.kind,
+            crate::error::GeminiErrorKind::InvalidArgument
+        ));
+    }
+
+    #[test]
+    fn display_name_is_never_serialized() {
+        let part = Part::Data {
+            data: vec![1, 2, 3],
+            mime_type: "image/png".to_string(),
+            display_name: Some("chart".to_string()),
+        };
+
+        assert!(matches!(
+            part.clone(),
+            Part::Data { display_name: Some(name), .. } if name == "chart"
+        ));
+        let json = serde_json::to_value(part).unwrap();
+        assert!(json["inlineData"].get("displayName").is_none());
+        let part: Part = serde_json::from_value(json).unwrap();
+        assert!(matches!(
+            part,
+            Part::Data {
+                display_name: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn markdown_images_become_data_parts() {
+        let dir = std::env::temp_dir().join(format!("rusty-gemini-md-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let png = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 0, 0];
+        fs::write(dir.join("chart.png"), png).unwrap();
+
+        let md = "# Sales\n\nSee the chart:\n\n![chart](chart.png \"Q3\")\n\nIt grew. ![logo](https://example.com/logo.png)";
+        let content = Content::from_markdown_with_images(md, &dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(content.role, Role::User));
+        assert_eq!(content.parts.len(), 3);
+        assert!(
+            matches!(&content.parts[0], Part::Text(text) if text == "# Sales\n\nSee the chart:")
+        );
+        match &content.parts[1] {
+            Part::Data {
+                data,
+                mime_type,
+                display_name,
+            } => {
+                assert_eq!(data, &png);
+                assert_eq!(mime_type, "image/png");
+                assert_eq!(display_name.as_deref(), Some("chart.png"));
+            }
+            part => panic!("expected a data part, got {part:?}"),
+        }
+        assert!(matches!(
+            &content.parts[2],
+            Part::Text(text) if text == "It grew. ![logo](https://example.com/logo.png)"
+        ));
+
+        let err = Content::from_markdown_with_images("<img src='missing.png'>", &dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn markdown_images_outside_base_dir_are_rejected() {
+        let root =
+            std::env::temp_dir().join(format!("rusty-gemini-md-root-{}", std::process::id()));
+        let dir = root.join("docs");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(root.join("secret.txt"), "secret").unwrap();
+        fs::write(dir.join("notes.txt"), "notes").unwrap();
+        let secret = root.join("secret.txt").display().to_string();
+
+        let results = [
+            Content::from_markdown_with_images("![x](../secret.txt)", &dir),
+            Content::from_markdown_with_images(&format!("![x]({secret})"), &dir),
+            Content::from_markdown_with_images("<img src=\"../docs/../secret.txt\">", &dir),
+        ];
+        let inside = Content::from_markdown_with_images("![x](./notes.txt)", &dir);
+        fs::remove_dir_all(&root).unwrap();
+
+        for result in results {
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(inside.unwrap().parts.len(), 1);
+    }
+}
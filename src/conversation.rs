@@ -0,0 +1,148 @@
+//! A chat that takes care of retries and history length, for callers who just want answers.
+
+use crate::{
+    chat::ChatSession, content::Content, error::GeminiError, model::GenerativeModel,
+    retry::RetryConfig,
+};
+
+/// A [ChatSession] that retries transient failures and drops the oldest turns when
+/// the history outgrows the model's context window.
+///
+/// ```no_run
+/// # async fn run(model: rusty_gemini::model::GenerativeModel) -> Result<(), rusty_gemini::error::GeminiError> {
+/// use rusty_gemini::conversation::Conversation;
+///
+/// let mut conversation = Conversation::new(&model);
+/// let answer = conversation.ask("What is the capital of France?").await?;
+/// let follow_up = conversation.ask("And its population?").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Conversation {
+    chat: ChatSession,
+    retry: RetryConfig,
+    max_history_tokens: Option<usize>,
+}
+
+impl Conversation {
+    /// Starts an empty conversation with `model`, using the default [RetryConfig].
+    pub fn new(model: &GenerativeModel) -> Self {
+        Self {
+            chat: model.start_chat(Vec::new()),
+            retry: RetryConfig::default(),
+            max_history_tokens: None,
+        }
+    }
+
+    /// Sets how failed messages are retried.
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Limits the history to about `max_tokens` tokens, instead of the model's input
+    /// token limit.
+    pub fn with_max_history_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_history_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Sends `text` and returns the text of the reply.
+    ///
+    /// Before sending, the oldest exchanges are dropped until the history and `text`
+    /// fit in the token limit (estimated with [Content::estimate_tokens]). Models
+    /// without a known limit keep their whole history unless
+    /// [Conversation::with_max_history_tokens] was used.
+    pub async fn ask(&mut self, text: &str) -> Result<String, GeminiError> {
+        let message = Content::user(text);
+        let limit = self
+            .max_history_tokens
+            .or_else(|| self.chat.model.model.input_token_limit());
+        if let Some(limit) = limit {
+            self.chat
+                .truncate_history(limit.saturating_sub(message.estimate_tokens()));
+        }
+
+        let mut retries = 0;
+        loop {
+            match self.chat.send_message(message.clone()).await {
+                Ok(response) => return response.into_text(),
+                Err(err) if err.is_retryable() && retries < self.retry.max_retries => {
+                    tokio::time::sleep(self.retry.backoff(retries)).await;
+                    retries += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// The underlying chat session, e.g. to read its history.
+    pub fn chat(&self) -> &ChatSession {
+        &self.chat
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{content::Role, mock::MockServer};
+
+    fn reply(text: &str) -> String {
+        serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": text }] } }],
+            "usageMetadata": {}
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn ask_retries_and_truncates() {
+        let unavailable =
+            r#"{"error": {"code": 503, "message": "overloaded", "status": "UNAVAILABLE"}}"#;
+        let server = MockServer::start(vec![
+            (200, reply("first answer")),
+            (503, unavailable.to_string()),
+            (200, reply("second answer")),
+            (200, reply("third answer")),
+        ])
+        .await;
+        let model = server.model().build();
+        let mut conversation = Conversation::new(&model)
+            .with_retry(RetryConfig {
+                max_retries: 1,
+                initial_backoff: Duration::ZERO,
+                max_backoff: Duration::ZERO,
+            })
+            // Room for about two short exchanges.
+            .with_max_history_tokens(12);
+
+        assert_eq!(
+            conversation.ask("first question").await.unwrap(),
+            "first answer"
+        );
+        assert_eq!(
+            conversation.ask("second question").await.unwrap(),
+            "second answer"
+        );
+        assert_eq!(
+            conversation.ask("third question").await.unwrap(),
+            "third answer"
+        );
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        // The retried request is identical to the failed one.
+        assert_eq!(requests[1].json(), requests[2].json());
+        // The first exchange was dropped to make room for the third question.
+        let contents = &requests[3].json()["contents"];
+        assert_eq!(contents.as_array().unwrap().len(), 3);
+        assert_eq!(contents[0]["parts"][0]["text"], "second question");
+
+        let history = conversation.chat().history();
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[0].role, Role::User);
+    }
+}
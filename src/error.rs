@@ -4,6 +4,10 @@ use crate::api::GeminiGenericError;
 pub struct GeminiError {
     pub kind: GeminiErrorKind,
     pub message: String,
+    /// The request context of the failed call, see [GenerativeModelBuilder::request_context].
+    ///
+    /// [GenerativeModelBuilder::request_context]: crate::model::GenerativeModelBuilder::request_context
+    pub context: Option<serde_json::Value>,
 }
 
 impl GeminiError {
@@ -11,8 +15,38 @@ impl GeminiError {
         Self {
             kind: GeminiErrorKind::Other,
             message: msg.to_string(),
+            context: None,
         }
     }
+
+    pub(crate) fn invalid_argument(msg: &str) -> Self {
+        Self {
+            kind: GeminiErrorKind::InvalidArgument,
+            message: msg.to_string(),
+            context: None,
+        }
+    }
+
+    /// Attaches the request context of the call that failed.
+    pub(crate) fn with_context(mut self, context: Option<serde_json::Value>) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// Returns whether the request that produced this error is worth retrying.
+    ///
+    /// Rate limiting, transient server failures, timeouts and dropped connections
+    /// are retryable; everything else (bad arguments, permissions, etc) will fail again.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind,
+            GeminiErrorKind::ResourceExhausted
+                | GeminiErrorKind::ServiceUnavailable
+                | GeminiErrorKind::Internal
+                | GeminiErrorKind::Timeout
+                | GeminiErrorKind::Connection
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -21,9 +55,21 @@ pub enum GeminiErrorKind {
     InvalidArgument,
     UnsupportedCountry,
     PermissionDenied,
+    /// The API key is missing, invalid or not allowed to use the API.
+    Unauthenticated,
+    /// A rate limit (e.g. requests per minute) was hit; retrying a little later should work.
     ResourceExhausted,
+    /// A long-term quota (e.g. requests per day) is used up, so retrying won't help
+    /// until it resets.
+    QuotaExhausted,
     Internal,
     ServiceUnavailable,
+    /// The request did not complete in time.
+    Timeout,
+    /// The connection to the API failed or was interrupted.
+    Connection,
+    /// The prompt or the response was blocked (e.g. for safety), so there is no usable output.
+    Blocked,
     /// This can be returned due to errors in t serialization etc
     /// And not necessarily by the Gemini API
     Other,
@@ -35,6 +81,8 @@ impl From<GeminiGenericError> for GeminiError {
             "INVALID_ARGUMENT" => GeminiErrorKind::InvalidArgument,
             "FAILED_PRECONDITION" => GeminiErrorKind::UnsupportedCountry,
             "PERMISSION_DENIED" => GeminiErrorKind::PermissionDenied,
+            "UNAUTHENTICATED" => GeminiErrorKind::Unauthenticated,
+            "RESOURCE_EXHAUSTED" if is_quota_exhausted(&value) => GeminiErrorKind::QuotaExhausted,
             "RESOURCE_EXHAUSTED" => GeminiErrorKind::ResourceExhausted,
             "INTERNAL" => GeminiErrorKind::Internal,
             "UNAVAILABLE" => GeminiErrorKind::ServiceUnavailable,
@@ -42,6 +90,121 @@ impl From<GeminiGenericError> for GeminiError {
         };
 
         let message = value.message;
-        Self { kind, message }
+        Self {
+            kind,
+            message,
+            context: None,
+        }
+    }
+}
+
+/// Whether a `RESOURCE_EXHAUSTED` error is about a daily quota rather than a rate limit.
+///
+/// The quota ids in the error's `QuotaFailure` details (e.g.
+/// `GenerateRequestsPerDayPerProjectPerModel`) are checked first, then the message.
+fn is_quota_exhausted(error: &GeminiGenericError) -> bool {
+    let quota_ids: Vec<&str> = error
+        .details
+        .iter()
+        .filter_map(|detail| detail.get("violations")?.as_array())
+        .flatten()
+        .filter_map(|violation| violation.get("quotaId")?.as_str())
+        .collect();
+    if !quota_ids.is_empty() {
+        return quota_ids.iter().any(|id| id.contains("PerDay"));
+    }
+    let message = error.message.to_lowercase();
+    message.contains("per day") || message.contains("daily")
+}
+
+impl From<reqwest::Error> for GeminiError {
+    fn from(value: reqwest::Error) -> Self {
+        let kind = if value.is_timeout() {
+            GeminiErrorKind::Timeout
+        } else if value.is_connect() || value.is_request() || value.is_body() {
+            GeminiErrorKind::Connection
+        } else {
+            GeminiErrorKind::Other
+        };
+
+        Self {
+            kind,
+            message: value.to_string(),
+            context: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(kind: GeminiErrorKind) -> GeminiError {
+        GeminiError {
+            kind,
+            message: String::new(),
+            context: None,
+        }
+    }
+
+    #[test]
+    fn retryable_kinds() {
+        assert!(error(GeminiErrorKind::ResourceExhausted).is_retryable());
+        assert!(error(GeminiErrorKind::ServiceUnavailable).is_retryable());
+        assert!(error(GeminiErrorKind::Internal).is_retryable());
+        assert!(error(GeminiErrorKind::Timeout).is_retryable());
+        assert!(error(GeminiErrorKind::Connection).is_retryable());
+    }
+
+    #[test]
+    fn non_retryable_kinds() {
+        assert!(!error(GeminiErrorKind::InvalidArgument).is_retryable());
+        assert!(!error(GeminiErrorKind::UnsupportedCountry).is_retryable());
+        assert!(!error(GeminiErrorKind::PermissionDenied).is_retryable());
+        assert!(!error(GeminiErrorKind::Blocked).is_retryable());
+        assert!(!error(GeminiErrorKind::Other).is_retryable());
+        assert!(!error(GeminiErrorKind::QuotaExhausted).is_retryable());
+    }
+
+    fn resource_exhausted(message: &str, details: Vec<serde_json::Value>) -> GeminiError {
+        GeminiError::from(GeminiGenericError {
+            code: 429,
+            message: message.to_string(),
+            status: "RESOURCE_EXHAUSTED".to_string(),
+            details,
+        })
+    }
+
+    #[test]
+    fn quota_exhausted_is_told_from_rate_limited() {
+        let violations = |quota_id: &str| {
+            vec![serde_json::json!({
+                "@type": "type.googleapis.com/google.rpc.QuotaFailure",
+                "violations": [{ "quotaId": quota_id }]
+            })]
+        };
+        let message =
+            "You exceeded your current quota, please check your plan and billing details.";
+
+        let daily = resource_exhausted(
+            message,
+            violations("GenerateRequestsPerDayPerProjectPerModel"),
+        );
+        assert!(matches!(daily.kind, GeminiErrorKind::QuotaExhausted));
+        assert!(!daily.is_retryable());
+
+        let per_minute = resource_exhausted(
+            message,
+            violations("GenerateRequestsPerMinutePerProjectPerModel"),
+        );
+        assert!(matches!(
+            per_minute.kind,
+            GeminiErrorKind::ResourceExhausted
+        ));
+        assert!(per_minute.is_retryable());
+
+        let by_message = resource_exhausted("Quota exceeded for requests per day.", vec![]);
+        assert!(matches!(by_message.kind, GeminiErrorKind::QuotaExhausted));
+        assert!(resource_exhausted("Resource has been exhausted.", vec![]).is_retryable());
     }
 }
@@ -0,0 +1,183 @@
+//! Helpers for comparing responses, e.g. when evaluating a prompt change.
+
+use std::fmt::Display;
+
+use crate::{
+    api::TaskType, error::GeminiError, model::GenerativeModel, EmbedContentConfig, GeminiResponse,
+};
+
+/// One line of a [TextDiff].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine {
+    /// A line present in both texts.
+    Same(String),
+    /// A line only present in the first text.
+    Removed(String),
+    /// A line only present in the second text.
+    Added(String),
+}
+
+/// A line-level diff between the texts of two responses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextDiff {
+    pub lines: Vec<DiffLine>,
+}
+
+impl TextDiff {
+    /// Returns whether both texts are the same.
+    pub fn is_identical(&self) -> bool {
+        self.lines
+            .iter()
+            .all(|line| matches!(line, DiffLine::Same(_)))
+    }
+}
+
+impl Display for TextDiff {
+    /// Formats the diff like `diff -u`, prefixing lines with ` `, `-` or `+`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            match line {
+                DiffLine::Same(line) => writeln!(f, " {line}")?,
+                DiffLine::Removed(line) => writeln!(f, "-{line}")?,
+                DiffLine::Added(line) => writeln!(f, "+{line}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Diffs the text of the first candidate of `a` against that of `b`, line by line.
+///
+/// A response without text is treated as empty.
+pub fn text_diff(a: &GeminiResponse, b: &GeminiResponse) -> TextDiff {
+    let a = response_text(a);
+    let b = response_text(b);
+    let a: Vec<&str> = a.lines().collect();
+    let b: Vec<&str> = b.lines().collect();
+
+    // lcs[i][j] is the length of the longest common subsequence of a[i..] and b[j..].
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            lines.push(DiffLine::Same(a[i].to_string()));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            lines.push(DiffLine::Removed(a[i].to_string()));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(b[j].to_string()));
+            j += 1;
+        }
+    }
+    lines.extend(
+        a[i..]
+            .iter()
+            .map(|line| DiffLine::Removed(line.to_string())),
+    );
+    lines.extend(b[j..].iter().map(|line| DiffLine::Added(line.to_string())));
+    TextDiff { lines }
+}
+
+/// Embeds the texts of `a` and `b` with `model` and returns their cosine similarity.
+///
+/// `model` must be an embedding model, such as `text-embedding-004`.
+pub async fn embedding_similarity(
+    model: &GenerativeModel,
+    a: &GeminiResponse,
+    b: &GeminiResponse,
+) -> Result<f64, GeminiError> {
+    let config = EmbedContentConfig {
+        task_type: Some(TaskType::SemanticSimilarity),
+        ..Default::default()
+    };
+    let response = model
+        .batch_embed_content(vec![
+            (response_text(a).into(), config.clone()),
+            (response_text(b).into(), config),
+        ])
+        .await?;
+    match response.embeddings.as_slice() {
+        [a, b] => Ok(cosine_similarity(&a.values, &b.values)),
+        embeddings => Err(GeminiError::message(&format!(
+            "expected 2 embeddings, got {}",
+            embeddings.len()
+        ))),
+    }
+}
+
+/// The cosine similarity of two vectors, or 0 if either has no magnitude.
+pub fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let dot: f64 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm = |v: &[f64]| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+fn response_text(response: &GeminiResponse) -> String {
+    response
+        .candidates
+        .first()
+        .and_then(|candidate| candidate.text())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+
+    fn response(text: &str) -> GeminiResponse {
+        serde_json::from_value(serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": text }] } }],
+            "usageMetadata": {}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn diff_of_differing_responses() {
+        let a = response("Paris is the capital.\nIt is in France.\nPopulation: 2M");
+        let b = response("Paris is the capital.\nIt is in Europe.\nPopulation: 2M\nThe end.");
+
+        let diff = text_diff(&a, &b);
+        assert!(!diff.is_identical());
+        assert_eq!(
+            diff.to_string(),
+            " Paris is the capital.\n-It is in France.\n+It is in Europe.\n Population: 2M\n+The end.\n"
+        );
+        assert!(text_diff(&a, &a).is_identical());
+    }
+
+    #[tokio::test]
+    async fn similarity_of_embeddings() {
+        let server = MockServer::ok(serde_json::json!({
+            "embeddings": [{ "values": [1.0, 0.0] }, { "values": [1.0, 1.0] }]
+        }))
+        .await;
+        let model = server.model().model("text-embedding-004").build();
+
+        let similarity = embedding_similarity(&model, &response("a"), &response("b"))
+            .await
+            .unwrap();
+        assert!((similarity - std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-9);
+        let request = server.requests()[0].json();
+        assert_eq!(request["requests"][1]["content"]["parts"][0]["text"], "b");
+    }
+}
@@ -2,24 +2,31 @@ use serde::{Deserialize, Serialize};
 
 use crate::content::Content;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct GroundingAtrribution {
     pub source_id: AtrributionSourceId,
     pub content: Content,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct GroundingMetadata {
-    pub grounding_chunk: GroundingChunk,
+    /// The sources the response was grounded in.
+    #[serde(default)]
+    pub grounding_chunks: Vec<GroundingChunk>,
+    /// Which parts of the response each source supports.
+    #[serde(default)]
     pub grounding_supports: Vec<GroundingSupport>,
+    #[serde(default)]
     pub web_search_queries: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub search_entry_point: Option<SearchEntryPoint>,
-    pub retrieval_metadata: RetrievalMetadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retrieval_metadata: Option<RetrievalMetadata>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub enum AtrributionSourceId {
     #[serde(rename_all = "camelCase")]
@@ -33,13 +40,13 @@ pub enum AtrributionSourceId {
     },
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SemanticRetrieverChunk {
     pub source: String,
     pub chunk: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum GroundingChunk {
     /// A chunk from the web
     #[serde(rename = "web")]
@@ -51,7 +58,7 @@ pub enum GroundingChunk {
     },
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct GroundingSupport {
     pub grounding_chunk_indices: Vec<i32>,
@@ -59,20 +66,22 @@ pub struct GroundingSupport {
     pub segment: Segment,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SearchEntryPoint {
     pub rendered_content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub sdk_blob: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct RetrievalMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub google_search_dynamic_retrieval_score: Option<f64>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Segment {
     pub part_index: i32,
@@ -91,11 +100,34 @@ pub struct GoogleSearchRetrieval {
 #[serde(rename_all = "camelCase")]
 pub struct DynamicRetrievalConfig {
     pub mode: Mode,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub dynamic_threshold: Option<f32>,
 }
 
 #[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Mode {
     ModeUnspecified,
     ModeDynamic,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_serializes_to_api_values() {
+        assert_eq!(
+            serde_json::to_value(Mode::ModeUnspecified).unwrap(),
+            "MODE_UNSPECIFIED"
+        );
+        assert_eq!(
+            serde_json::to_value(DynamicRetrievalConfig {
+                mode: Mode::ModeDynamic,
+                dynamic_threshold: Some(0.5),
+            })
+            .unwrap(),
+            serde_json::json!({ "mode": "MODE_DYNAMIC", "dynamicThreshold": 0.5 })
+        );
+    }
+}
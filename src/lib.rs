@@ -1,17 +1,34 @@
+use std::collections::HashMap;
+
 use api::{
-    Candidate, ContentEmbedding, GenerationConfig, PromptFeedback, SafetySetting, TaskType, Tool,
-    UsageMetadata,
+    Candidate, ContentEmbedding, FinishReason, GenerationConfig, PromptFeedback, SafetySetting,
+    TaskType, Tool, UsageMetadata,
 };
-use content::Content;
+use content::{Content, Part};
+use error::{GeminiError, GeminiErrorKind};
+
 use serde::{Deserialize, Serialize};
 
+pub mod agent;
 pub mod api;
+pub mod batch;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod cache;
 pub mod chat;
 pub mod content;
+pub mod conversation;
 pub mod schema;
+pub mod stream;
 pub mod grounding;
 pub mod model;
+pub mod pricing;
 pub mod error;
+pub mod eval;
+pub mod retry;
+
+#[cfg(test)]
+mod mock;
 
 pub static BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
 
@@ -22,22 +39,225 @@ pub struct GeminiRequest {
     pub tools: Option<Vec<Tool>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub safety_settings: Option<Vec<SafetySetting>>,
+    /// Sent without its role, which the API doesn't expect on system instructions.
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "ser_system_instruction")]
     pub system_instruction: Option<Content>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub generation_config: Option<GenerationConfig>,
+    /// Key-value labels attached to the request, e.g. for billing attribution.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<HashMap<String, String>>,
 }
-#[derive(Debug, Deserialize)]
+
+fn ser_system_instruction<S>(content: &Option<Content>, ser: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    #[derive(Serialize)]
+    struct SystemInstruction<'a> {
+        parts: &'a [Part],
+    }
+
+    content
+        .as_ref()
+        .map(|content| SystemInstruction {
+            parts: &content.parts,
+        })
+        .serialize(ser)
+}
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct GeminiResponse {
+    /// The generated candidates; empty when the prompt was blocked.
+    #[serde(default)]
     pub candidates: Vec<Candidate>,
     pub usage_metadata: UsageMetadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub prompt_feedback: Option<PromptFeedback>,
+    /// The exact model version that generated this response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model_version: Option<String>,
+    /// The identifier of this response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_id: Option<String>,
 }
 
 impl GeminiResponse {
     pub fn text(&self) -> Option<String> {
-        self.candidates[0].text()
+        self.candidates.first().and_then(Candidate::text)
+    }
+
+    /// Returns the first candidate's [text](Candidate::text_opt), or `None` if there is
+    /// no candidate or it has no text parts.
+    pub fn text_opt(&self) -> Option<String> {
+        self.candidates.first().and_then(Candidate::text_opt)
+    }
+
+    /// Returns the candidates ranked by average log probability, best first.
+    ///
+    /// Candidates without logprobs come after those with them, keeping their original order.
+    pub fn ranked_candidates(&self) -> Vec<&Candidate> {
+        let mut candidates: Vec<&Candidate> = self.candidates.iter().collect();
+        candidates.sort_by(|a, b| {
+            b.avg_logprobs
+                .partial_cmp(&a.avg_logprobs)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        candidates
+    }
+
+    /// Returns the first candidate's text (empty if there is none) along with the usage.
+    pub fn text_and_usage(&self) -> (String, &UsageMetadata) {
+        let text = self
+            .candidates
+            .first()
+            .and_then(Candidate::text)
+            .unwrap_or_default();
+        (text, &self.usage_metadata)
+    }
+
+    /// Returns whether part of the prompt was served from cached content.
+    ///
+    /// This is inferred from `usage_metadata.cached_content_token_count` being
+    /// reported and greater than zero; the API has no explicit cache-hit flag.
+    pub fn was_cached(&self) -> bool {
+        self.usage_metadata
+            .cached_content_token_count
+            .is_some_and(|count| count > 0)
+    }
+
+    /// Returns the first candidate's text, or an error explaining why there is none.
+    ///
+    /// Text is returned when generation stopped normally or hit `MAX_TOKENS`. A blocked
+    /// prompt or a response stopped for any other reason (e.g. `SAFETY`) gives a
+    /// [GeminiErrorKind::Blocked] error naming the reason.
+    pub fn into_text(self) -> Result<String, GeminiError> {
+        let Some(candidate) = self.candidates.into_iter().next() else {
+            let message = match self.prompt_feedback {
+                Some(PromptFeedback {
+                    block_reason: Some(reason),
+                    block_reason_message,
+                    ..
+                }) => format!(
+                    "the prompt was blocked ({reason:?}){}",
+                    block_reason_message
+                        .map(|message| format!(": {message}"))
+                        .unwrap_or_default()
+                ),
+                _ => "the response has no candidates".to_string(),
+            };
+            return Err(GeminiError {
+                kind: GeminiErrorKind::Blocked,
+                message,
+                context: None,
+            });
+        };
+
+        match &candidate.finish_reason {
+            None | Some(FinishReason::Stop) | Some(FinishReason::MaxTokens) => candidate
+                .text()
+                .ok_or_else(|| GeminiError::message("the response has no text")),
+            Some(reason) => Err(GeminiError {
+                kind: GeminiErrorKind::Blocked,
+                message: format!(
+                    "the response stopped because of {reason:?} without usable text; safety ratings: {:?}",
+                    candidate.safety_ratings.unwrap_or_default()
+                ),
+                context: None,
+            }),
+        }
+    }
+
+    /// Finds the first JSON object or array in the first candidate's text.
+    ///
+    /// A fenced block (```` ```json ```` or a bare ```` ``` ````) is preferred; otherwise
+    /// the first balanced `{...}` or `[...]` in the text is returned. The block is
+    /// searched for within each text part, so it is not found if split across parts.
+    pub fn extract_json_block(&self) -> Option<&str> {
+        self.candidates
+            .first()?
+            .content
+            .parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Text(text) | Part::TimestampedText { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .find_map(|text| fenced_json_block(text).or_else(|| bare_json_block(text)))
+    }
+}
+
+fn fenced_json_block(text: &str) -> Option<&str> {
+    let mut rest = text;
+    while let Some(start) = rest.find("```") {
+        let after_fence = &rest[start + 3..];
+        // skip the info string (e.g. `json`) up to the end of the opening line
+        let body_start = after_fence.find('\n').map(|i| i + 1)?;
+        let body = &after_fence[body_start..];
+        let end = body.find("```")?;
+        let block = body[..end].trim();
+        if block.starts_with('{') || block.starts_with('[') {
+            return Some(block);
+        }
+        rest = &body[end + 3..];
+    }
+    None
+}
+
+fn bare_json_block(text: &str) -> Option<&str> {
+    let start = text.find(['{', '['])?;
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text[start..].char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' | '[' => depth += 1,
+            '}' | ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&text[start..start + i + 1]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The parts of a response most callers need, as returned by
+/// [GenerativeModel::complete](model::GenerativeModel::complete).
+#[derive(Debug, Clone)]
+pub struct Completion {
+    /// The text of the first candidate, or empty if there is none.
+    pub text: String,
+    pub usage: UsageMetadata,
+    /// Why the first candidate stopped, or `None` if there is no candidate.
+    pub finish_reason: Option<FinishReason>,
+}
+
+impl From<GeminiResponse> for Completion {
+    fn from(response: GeminiResponse) -> Self {
+        let (text, _) = response.text_and_usage();
+        Completion {
+            text,
+            finish_reason: response
+                .candidates
+                .into_iter()
+                .next()
+                .and_then(|candidate| candidate.finish_reason),
+            usage: response.usage_metadata,
+        }
     }
 }
 
@@ -49,11 +269,34 @@ pub struct EmbedContentRequest {
     pub config: EmbedContentConfig,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct EmbedContentResponse {
     pub embedding: ContentEmbedding,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchEmbedContentsRequest {
+    pub requests: Vec<BatchEmbedContentsItem>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchEmbedContentsItem {
+    /// The model name, in the form `models/{model}`.
+    pub model: String,
+    #[serde(flatten)]
+    pub request: EmbedContentRequest,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchEmbedContentsResponse {
+    pub embeddings: Vec<ContentEmbedding>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CountTokensRequest {
+    pub contents: Vec<Content>,
+}
+
 #[derive(Debug, Clone, Serialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct EmbedContentConfig {
@@ -61,10 +304,194 @@ pub struct EmbedContentConfig {
     pub task_type: Option<TaskType>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub title: Option<String>,
+    /// Truncates the embedding to this many dimensions.
+    ///
+    /// `text-embedding-004` supports any size from 1 to 768. Custom models are not
+    /// checked client-side.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub output_dimensionality: Option<i32>,
 }
 
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    fn response_with_text(text: &str) -> GeminiResponse {
+        serde_json::from_value(serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": text }] } }],
+            "usageMetadata": {}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn model_version_and_response_id() {
+        let response: GeminiResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [],
+            "usageMetadata": {},
+            "modelVersion": "gemini-1.5-flash-002",
+            "responseId": "abc123"
+        }))
+        .unwrap();
+        assert_eq!(
+            response.model_version.as_deref(),
+            Some("gemini-1.5-flash-002")
+        );
+        assert_eq!(response.response_id.as_deref(), Some("abc123"));
+
+        let response = response_with_text("hi");
+        assert_eq!(response.model_version, None);
+        assert_eq!(response.response_id, None);
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let json = serde_json::json!({
+            "candidates": [{
+                "content": { "role": "model", "parts": [{ "text": "hi" }] },
+                "finishReason": "STOP",
+                "safetyRatings": [
+                    { "category": "HARM_CATEGORY_HATE_SPEECH", "probability": "NEGLIGIBLE" }
+                ],
+                "citationMetadata": {
+                    "citationSources": [{ "startIndex": 0, "endIndex": 2, "uri": "https://example.com" }]
+                }
+            }],
+            "usageMetadata": { "promptTokenCount": 3, "candidatesTokenCount": 1, "totalTokenCount": 4 },
+            "modelVersion": "gemini-1.5-flash-002"
+        });
+
+        let response: GeminiResponse = serde_json::from_value(json.clone()).unwrap();
+        let serialized = serde_json::to_value(&response).unwrap();
+        assert_eq!(serialized, json);
+
+        let response: GeminiResponse = serde_json::from_value(serialized.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&response).unwrap(), serialized);
+    }
+
+    #[test]
+    fn was_cached() {
+        let with_cached = |count: Option<i32>| -> GeminiResponse {
+            serde_json::from_value(serde_json::json!({
+                "candidates": [],
+                "usageMetadata": { "cachedContentTokenCount": count }
+            }))
+            .unwrap()
+        };
+        assert!(with_cached(Some(2048)).was_cached());
+        assert!(!with_cached(Some(0)).was_cached());
+        assert!(!with_cached(None).was_cached());
+    }
+
+    #[test]
+    fn into_text_success() {
+        assert_eq!(response_with_text("hello").into_text().unwrap(), "hello");
+    }
+
+    #[test]
+    fn into_text_blocked() {
+        let response: GeminiResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [{
+                "content": { "role": "model", "parts": [] },
+                "finishReason": "SAFETY",
+                "safetyRatings": [
+                    { "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "probability": "HIGH" }
+                ]
+            }],
+            "usageMetadata": {}
+        }))
+        .unwrap();
+
+        let err = response.into_text().unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::Blocked));
+        assert!(err.message.contains("Safety"));
+        assert!(err.message.contains("DangerousContent"));
+
+        let response: GeminiResponse =
+            serde_json::from_value(serde_json::json!({ "usageMetadata": {} })).unwrap();
+        let err = response.into_text().unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::Blocked));
+    }
+
+    #[test]
+    fn blocked_prompt_has_no_text() {
+        let response: GeminiResponse = serde_json::from_value(serde_json::json!({
+            "promptFeedback": { "blockReason": "SAFETY" },
+            "usageMetadata": {}
+        }))
+        .unwrap();
+        assert!(response.candidates.is_empty());
+        assert_eq!(response.text(), None);
+    }
+
+    #[test]
+    fn extract_fenced_json_block() {
+        let response = response_with_text(
+            "Here you go:\n```json\n{\"a\": {\"b\": [1, 2]}}\n```\nAnything else?",
+        );
+        assert_eq!(
+            response.extract_json_block(),
+            Some("{\"a\": {\"b\": [1, 2]}}")
+        );
+    }
+
+    #[test]
+    fn extract_bare_json_block() {
+        let response = response_with_text("The answer is {\"x\": \"}{\", \"y\": {}} as requested.");
+        assert_eq!(
+            response.extract_json_block(),
+            Some("{\"x\": \"}{\", \"y\": {}}")
+        );
+        assert_eq!(
+            response_with_text("no json here").extract_json_block(),
+            None
+        );
+    }
+
+    #[test]
+    fn system_instruction_has_no_role() {
+        let request = GeminiRequest {
+            contents: vec![Content::user("hi")],
+            tools: None,
+            safety_settings: None,
+            system_instruction: Some(Content::user("Be terse.")),
+            generation_config: None,
+            labels: None,
+        };
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(
+            json["system_instruction"],
+            serde_json::json!({ "parts": [{ "text": "Be terse." }] })
+        );
+        assert_eq!(json["contents"][0]["role"], "user");
+    }
+
+    #[test]
+    fn candidates_are_ranked_by_avg_logprobs() {
+        let candidate = |text: &str, avg_logprobs: Option<f64>| {
+            serde_json::json!({
+                "content": { "role": "model", "parts": [{ "text": text }] },
+                "avgLogprobs": avg_logprobs
+            })
+        };
+        let response: GeminiResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [
+                candidate("none-1", None),
+                candidate("low", Some(-1.5)),
+                candidate("high", Some(-0.2)),
+                candidate("none-2", None),
+                candidate("mid", Some(-0.7)),
+            ],
+            "usageMetadata": {}
+        }))
+        .unwrap();
+
+        let ranked: Vec<String> = response
+            .ranked_candidates()
+            .into_iter()
+            .filter_map(Candidate::text)
+            .collect();
+        assert_eq!(ranked, ["high", "mid", "low", "none-1", "none-2"]);
+    }
+}
@@ -1,20 +1,74 @@
-use core::str;
-use std::{borrow::Cow, fmt::Display};
+use std::{
+    borrow::Cow, collections::HashMap, fmt::Display, fs, future::Future, io, ops::RangeInclusive,
+    path::Path, pin::pin, sync::Arc,
+};
 
-use futures_util::{Stream, StreamExt};
+use futures_util::{stream, Stream, StreamExt};
+use reqwest::Method;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinHandle,
+};
 
 use crate::{
-    api::{GeminiGenericErrorResponse, GenerationConfig, SafetySetting, Tool},
-    chat::ChatSession,
-    content::Content,
+    api::{
+        validate_safety_settings, Candidate, ContentEmbedding, CountTokenResponse, FinishReason,
+        GeminiGenericErrorResponse, GenerationConfig, HarmBlockThreshold, HarmCategory,
+        SafetySetting, SafetySettings, TaskType, Tool, UsageMetadata,
+    },
+    cache::ResponseCache,
+    chat::{CandidateSelector, ChatSession},
+    content::{Content, Role},
     error::{GeminiError, GeminiErrorKind},
-    EmbedContentConfig, EmbedContentRequest, EmbedContentResponse, GeminiRequest, GeminiResponse,
+    eval::cosine_similarity,
+    pricing::{CostEstimate, PriceTable},
+    stream::{resilient_text_stream, StreamDecoder, StreamFormat},
+    BatchEmbedContentsItem, BatchEmbedContentsRequest, BatchEmbedContentsResponse, Completion,
+    CountTokensRequest, EmbedContentConfig, EmbedContentRequest, EmbedContentResponse,
+    GeminiRequest, GeminiResponse,
 };
 
 /// The base URL for the Gemini API.
 pub static BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
 
+/// How many chunks [GenerativeModel::generate_content_channel] buffers before waiting.
+const CHANNEL_CAPACITY: usize = 4;
+
+/// Called before a request is sent, with the request and its context.
+pub type RequestCallback = Arc<dyn Fn(&GeminiRequest, Option<&serde_json::Value>) + Send + Sync>;
+
+/// Called once a request completes, with its result and context.
+pub type ResponseCallback =
+    Arc<dyn Fn(Result<&GeminiResponse, &GeminiError>, Option<&serde_json::Value>) + Send + Sync>;
+
+/// The callbacks run around content generation: [GenerativeModel::generate_content],
+/// [GenerativeModel::generate_content_streamed] and the methods built on them.
+/// Token counting and embeddings don't run them.
+///
+/// For a streamed call, `on_response` runs for each chunk, or once with the error if
+/// the stream fails to start.
+#[derive(Clone, Default)]
+pub struct Callbacks {
+    pub on_request: Option<RequestCallback>,
+    pub on_response: Option<ResponseCallback>,
+}
+
+impl std::fmt::Debug for Callbacks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Callbacks")
+            .field("on_request", &self.on_request.is_some())
+            .field("on_response", &self.on_response.is_some())
+            .finish()
+    }
+}
+
 /// Represents a Generative Model instance.
+///
+/// A model is `Send + Sync` and cheap to clone (clones share one HTTP client), so it
+/// can be shared across tasks, e.g. in web server state, and the futures returned by
+/// its methods can be spawned on a multi-threaded runtime.
 #[derive(Debug, Clone)]
 pub struct GenerativeModel {
     /// The API key used to authenticate requests.
@@ -29,6 +83,29 @@ pub struct GenerativeModel {
     pub safety_settings: Option<Vec<SafetySetting>>,
     /// Optional tools that the model can use.
     pub tools: Option<Vec<Tool>>,
+    /// Optional labels attached to every request, e.g. for billing attribution.
+    pub labels: Option<HashMap<String, String>>,
+    /// The format used for streamed responses.
+    pub stream_format: StreamFormat,
+    /// The URL requests are sent to, up to and including the API version (e.g. `/v1beta`).
+    pub base_url: String,
+    /// Whether the API key is sent in the `x-goog-api-key` header (the default)
+    /// rather than the `key` query parameter, where it can end up in server and proxy logs.
+    pub auth_via_header: bool,
+    /// Whether sending tools to a model known not to support them is an error
+    /// rather than a logged warning.
+    pub strict_tool_check: bool,
+    /// The prices used by [GenerativeModel::estimate_cost].
+    pub price_table: PriceTable,
+    /// The cache consulted before generating content, shared by every clone of this model.
+    pub response_cache: Option<Arc<ResponseCache>>,
+    /// The callbacks run around every `generateContent` request.
+    pub callbacks: Callbacks,
+    /// Metadata passed to the callbacks and attached to errors, but never sent to the API.
+    pub request_context: Option<serde_json::Value>,
+    /// The HTTP client, used for every request. It is reference counted, so every
+    /// clone of this model (and its chat sessions) reuses one connection pool.
+    pub(crate) client: reqwest::Client,
 }
 
 /// A builder for creating a `GenerativeModel`.
@@ -40,6 +117,22 @@ pub struct GenerativeModelBuilder {
     pub safety_settings: Option<Vec<SafetySetting>>,
     pub generation_config: Option<GenerationConfig>,
     pub tools: Option<Vec<Tool>>,
+    pub labels: Option<HashMap<String, String>>,
+    pub stream_format: Option<StreamFormat>,
+    pub base_url: Option<String>,
+    pub auth_via_header: Option<bool>,
+    pub strict_tool_check: Option<bool>,
+    pub price_table: Option<PriceTable>,
+    pub response_cache: Option<Arc<ResponseCache>>,
+    pub callbacks: Option<Callbacks>,
+    pub request_context: Option<serde_json::Value>,
+    pub client: Option<reqwest::Client>,
+}
+
+impl Default for GenerativeModelBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GenerativeModelBuilder {
@@ -52,6 +145,16 @@ impl GenerativeModelBuilder {
             safety_settings: None,
             generation_config: None,
             tools: None,
+            labels: None,
+            stream_format: None,
+            base_url: None,
+            auth_via_header: None,
+            strict_tool_check: None,
+            price_table: None,
+            response_cache: None,
+            callbacks: None,
+            request_context: None,
+            client: None,
         }
     }
 
@@ -61,9 +164,9 @@ impl GenerativeModelBuilder {
         self
     }
 
-    /// Sets the specific `GeminiModel` to be used.
-    pub fn model(&mut self, model: GeminiModel) -> &mut Self {
-        self.model = Some(model);
+    /// Sets the specific `GeminiModel` to be used, either as a variant or by name (e.g. `"gemini-1.5-flash"`).
+    pub fn model(&mut self, model: impl Into<GeminiModel>) -> &mut Self {
+        self.model = Some(model.into());
         self
     }
 
@@ -73,12 +176,32 @@ impl GenerativeModelBuilder {
         self
     }
 
+    /// Sets the system instruction for the `GenerativeModel` to the contents of a text file.
+    pub fn system_instruction_from_file(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> io::Result<&mut Self> {
+        let instruction = fs::read_to_string(path)?;
+        Ok(self.system_instruction(instruction))
+    }
+
     /// Sets the generation configuration for the `GenerativeModel`.
     pub fn generation_config(&mut self, config: GenerationConfig) -> &mut Self {
         self.generation_config = Some(config);
         self
     }
 
+    /// Sets how many response candidates to generate, between 1 and 8.
+    ///
+    /// This is merged into the generation configuration; out of range values are
+    /// reported by [GenerativeModelBuilder::try_build].
+    pub fn candidate_count(&mut self, count: i32) -> &mut Self {
+        self.generation_config
+            .get_or_insert_with(GenerationConfig::default)
+            .candidate_count = Some(count);
+        self
+    }
+
     /// Adds a safety setting to the `GenerativeModel`.
     pub fn safety_setting(&mut self, setting: SafetySetting) -> &mut Self {
         if let Some(ref mut x) = self.safety_settings {
@@ -89,6 +212,30 @@ impl GenerativeModelBuilder {
         self
     }
 
+    /// Replaces the safety settings added so far with `settings`.
+    pub fn safety_settings(&mut self, settings: SafetySettings) -> &mut Self {
+        self.safety_settings = Some(settings.into());
+        self
+    }
+
+    /// Turns off blocking for every harm category, replacing any safety settings added so far.
+    ///
+    /// The model may then return harmful content; only use this where that is
+    /// acceptable, such as red-teaming or safety research. Logs a warning when called.
+    pub fn unsafe_disable_all_safety(&mut self) -> &mut Self {
+        log::warn!("all safety filters are disabled; responses may contain harmful content");
+        self.safety_settings = Some(
+            HarmCategory::ALL
+                .into_iter()
+                .map(|category| SafetySetting {
+                    category,
+                    threshold: HarmBlockThreshold::None,
+                })
+                .collect(),
+        );
+        self
+    }
+
     /// Adds a tool to the `GenerativeModel`.
     pub fn tool(&mut self, tool: Tool) -> &mut Self {
         if let Some(ref mut x) = self.tools {
@@ -99,20 +246,150 @@ impl GenerativeModelBuilder {
         self
     }
 
+    /// Adds a label to every request, e.g. to split billing by feature.
+    pub fn label(&mut self, key: &str, value: &str) -> &mut Self {
+        self.labels
+            .get_or_insert_with(HashMap::new)
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Sets the format used for streamed responses. Defaults to [StreamFormat::Sse].
+    pub fn stream_format(&mut self, format: StreamFormat) -> &mut Self {
+        self.stream_format = Some(format);
+        self
+    }
+
+    /// Sets the URL requests are sent to, up to and including the API version.
+    ///
+    /// Defaults to [BASE_URL].
+    pub fn base_url(&mut self, base_url: &str) -> &mut Self {
+        self.base_url = Some(base_url.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Sends requests to `host` (e.g. a regional endpoint or an internal gateway)
+    /// instead of `generativelanguage.googleapis.com`, keeping the standard paths.
+    ///
+    /// `host` may include a scheme and port (`http://localhost:8080`); HTTPS is used otherwise.
+    pub fn endpoint(&mut self, host: &str) -> &mut Self {
+        let host = host.trim_end_matches('/');
+        let version = BASE_URL.rsplit('/').next().unwrap_or_default();
+        self.base_url = Some(if host.contains("://") {
+            format!("{host}/{version}")
+        } else {
+            format!("https://{host}/{version}")
+        });
+        self
+    }
+
+    /// Sets whether the API key is sent in the `x-goog-api-key` header rather than the
+    /// `key` query parameter. Defaults to `true`, as recommended by Google.
+    pub fn auth_via_header(&mut self, enabled: bool) -> &mut Self {
+        self.auth_via_header = Some(enabled);
+        self
+    }
+
+    /// Sets whether sending tools to a model known not to support them (e.g. an
+    /// embedding model) fails with an `InvalidArgument` error instead of logging a
+    /// warning. Defaults to `false`.
+    pub fn strict_tool_check(&mut self, strict: bool) -> &mut Self {
+        self.strict_tool_check = Some(strict);
+        self
+    }
+
+    /// Sets the prices used to estimate costs, instead of [PriceTable::default].
+    pub fn price_table(&mut self, price_table: PriceTable) -> &mut Self {
+        self.price_table = Some(price_table);
+        self
+    }
+
+    /// Sets a cache that [GenerativeModel::generate_content] and friends check before
+    /// sending a request; see [ResponseCache] for which requests are cached.
+    pub fn response_cache(&mut self, cache: ResponseCache) -> &mut Self {
+        self.response_cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Sets a callback run before every `generateContent` request is sent.
+    pub fn on_request(
+        &mut self,
+        callback: impl Fn(&GeminiRequest, Option<&serde_json::Value>) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.callbacks
+            .get_or_insert_with(Callbacks::default)
+            .on_request = Some(Arc::new(callback));
+        self
+    }
+
+    /// Sets a callback run once every `generateContent` request completes, successfully or not.
+    pub fn on_response(
+        &mut self,
+        callback: impl Fn(Result<&GeminiResponse, &GeminiError>, Option<&serde_json::Value>)
+            + Send
+            + Sync
+            + 'static,
+    ) -> &mut Self {
+        self.callbacks
+            .get_or_insert_with(Callbacks::default)
+            .on_response = Some(Arc::new(callback));
+        self
+    }
+
+    /// Attaches metadata (e.g. a user or trace id) to requests. It is never sent to the
+    /// API; it is passed to the `on_request`/`on_response` callbacks and set as
+    /// [GeminiError::context] when a request fails.
+    ///
+    /// Set it on the model for a default, or on the per-call builder for one request.
+    pub fn request_context(&mut self, context: serde_json::Value) -> &mut Self {
+        self.request_context = Some(context);
+        self
+    }
+
+    /// Sets the HTTP client used to send requests, e.g. to configure proxies or timeouts.
+    pub fn client(&mut self, client: reqwest::Client) -> &mut Self {
+        self.client = Some(client);
+        self
+    }
+
     /// Builds the `GenerativeModel` with the configured values.
     ///
     /// # Panics
     ///
-    /// Panics if the `api_key` is not set.
+    /// Panics if the `api_key` is not set or the configuration is invalid.
     pub fn build(&mut self) -> GenerativeModel {
-        GenerativeModel {
-            api_key: self.api_key.take().expect("API key must be set"),
+        self.try_build()
+            .unwrap_or_else(|err| panic!("{}", err.message))
+    }
+
+    /// Builds the `GenerativeModel` with the configured values.
+    ///
+    /// Returns an `InvalidArgument` error if the `api_key` is not set or the configuration is invalid.
+    pub fn try_build(&mut self) -> Result<GenerativeModel, GeminiError> {
+        if self.api_key.is_none() {
+            return Err(GeminiError::invalid_argument("API key must be set"));
+        }
+        if let Some(generation_config) = &self.generation_config {
+            generation_config.validate()?;
+        }
+        Ok(GenerativeModel {
+            api_key: self.api_key.take().unwrap_or_default(),
             model: self.model.take().unwrap_or_default(),
             generation_config: self.generation_config.take(),
             system_instruction: self.system_instruction.take(),
             safety_settings: self.safety_settings.take(),
             tools: self.tools.take(),
-        }
+            labels: self.labels.take(),
+            stream_format: self.stream_format.take().unwrap_or_default(),
+            base_url: self.base_url.take().unwrap_or_else(|| BASE_URL.to_string()),
+            auth_via_header: self.auth_via_header.take().unwrap_or(true),
+            strict_tool_check: self.strict_tool_check.take().unwrap_or_default(),
+            price_table: self.price_table.take().unwrap_or_default(),
+            response_cache: self.response_cache.take(),
+            callbacks: self.callbacks.take().unwrap_or_default(),
+            request_context: self.request_context.take(),
+            client: self.client.take().unwrap_or_default(),
+        })
     }
 }
 
@@ -122,9 +399,78 @@ impl GenerativeModel {
         ChatSession {
             model: self.clone(),
             history,
+            grounding: Vec::new(),
+            selector: CandidateSelector::default(),
+        }
+    }
+
+    /// Returns a copy of this model that uses `model` instead, keeping every other setting.
+    pub fn clone_with_model(&self, model: GeminiModel) -> GenerativeModel {
+        GenerativeModel {
+            model,
+            ..self.clone()
         }
     }
 
+    /// Checks that the API key is accepted by fetching this model's metadata, which
+    /// costs no tokens. Call it on startup to fail fast on a bad key.
+    ///
+    /// A rejected key is reported as [GeminiErrorKind::Unauthenticated].
+    pub async fn validate_key(&self) -> Result<(), GeminiError> {
+        let url = format!("{}/models/{}", self.base_url, self.model);
+        let response = self
+            .authorized(Method::GET, url)
+            .send()
+            .await
+            .map_err(GeminiError::from)?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let text = response.text().await.map_err(GeminiError::from)?;
+        let mut error = serde_json::from_str::<GeminiGenericErrorResponse>(&text)
+            .map(|response| GeminiError::from(response.error))
+            .unwrap_or_else(|_| GeminiError::message(&format!("unexpected status {status}")));
+        // An invalid key is reported as INVALID_ARGUMENT, a key without access as PERMISSION_DENIED.
+        let rejected = matches!(
+            error.kind,
+            GeminiErrorKind::InvalidArgument | GeminiErrorKind::PermissionDenied
+        ) || matches!(status.as_u16(), 401 | 403);
+        if rejected {
+            error.kind = GeminiErrorKind::Unauthenticated;
+        }
+        Err(error)
+    }
+
+    /// Counts the tokens `prompt` uses, as the API would bill them.
+    pub async fn count_tokens(&self, prompt: Vec<Content>) -> Result<i32, GeminiError> {
+        let request = CountTokensRequest { contents: prompt };
+        let text = self.post(&self.model, "countTokens", &request).await?;
+        let response: CountTokenResponse = parse_response(&text)?;
+        Ok(response.total_tokens)
+    }
+
+    /// Estimates what generating a reply of `expected_output_tokens` to `prompt` would
+    /// cost, counting the prompt's tokens with the API and pricing them with the
+    /// model's [PriceTable].
+    ///
+    /// Returns an `InvalidArgument` error if the table has no pricing for the model.
+    pub async fn estimate_cost(
+        &self,
+        prompt: Vec<Content>,
+        expected_output_tokens: i32,
+    ) -> Result<CostEstimate, GeminiError> {
+        let pricing = *self.price_table.get(&self.model).ok_or_else(|| {
+            GeminiError::invalid_argument(&format!(
+                "no pricing for {}; add it to the model's price table",
+                self.model
+            ))
+        })?;
+        let input_tokens = self.count_tokens(prompt).await?;
+        Ok(pricing.estimate(input_tokens, expected_output_tokens))
+    }
+
     /// Generates content based on the provided prompt.
     pub async fn generate_content(
         &self,
@@ -134,6 +480,27 @@ impl GenerativeModel {
             .await
     }
 
+    /// Generates content and returns just its text, usage and finish reason.
+    pub async fn complete(&self, prompt: Vec<Content>) -> Result<Completion, GeminiError> {
+        Ok(self.generate_content(prompt).await?.into())
+    }
+
+    /// Generates content, asking the model to continue whenever it stops because of `MAX_TOKENS`.
+    ///
+    /// The partial output and a "continue" instruction are appended to the prompt and
+    /// sent again, for at most `max_rounds` requests in total. Returns the text of all
+    /// rounds joined together, or an `InvalidArgument` error if `max_rounds` is zero.
+    pub async fn generate_content_continued(
+        &self,
+        prompt: Vec<Content>,
+        max_rounds: usize,
+    ) -> Result<String, GeminiError> {
+        continue_generation(prompt, max_rounds, |contents| {
+            self.generate_content(contents)
+        })
+        .await
+    }
+
     /// Generates a stream of content responses based on the provided prompt.
     pub async fn generate_content_streamed(
         &self,
@@ -144,25 +511,94 @@ impl GenerativeModel {
     }
 
     /// Generates content based on the provided prompt, overriding some of the model's configurations using the provided builder.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "gemini.generate_content",
+            skip_all,
+            fields(prompt_token_count, candidates_token_count, total_token_count)
+        )
+    )]
     pub async fn generate_content_with(
         &self,
         prompt: Vec<Content>,
         config: GenerativeModelBuilder,
     ) -> Result<GeminiResponse, GeminiError> {
-        let response = self.send_request(prompt, config, false).await?;
+        let context = config
+            .request_context
+            .clone()
+            .or_else(|| self.request_context.clone());
+        let (model, request) = self
+            .validated_request(prompt, config)
+            .map_err(|err| err.with_context(context.clone()))?;
+        if let Some(on_request) = &self.callbacks.on_request {
+            on_request(&request, context.as_ref());
+        }
+        let result = self.generate(&model, &request).await;
+        if let Some(on_response) = &self.callbacks.on_response {
+            on_response(result.as_ref(), context.as_ref());
+        }
+        result.map_err(|err| err.with_context(context))
+    }
 
-        let text = response.text().await.map_err(|err| GeminiError {
-            kind: GeminiErrorKind::Other,
-            message: err.to_string(),
-        })?;
+    async fn generate(
+        &self,
+        model: &GeminiModel,
+        request: &GeminiRequest,
+    ) -> Result<GeminiResponse, GeminiError> {
+        let cache = self.response_cache.as_deref();
+        let key = cache.and_then(|cache| cache.key(model, request));
+        if let Some(cached) = cache.zip(key).and_then(|(cache, key)| cache.get(key)) {
+            return Ok(cached);
+        }
+        let response = self.send_request(model, request, None).await?;
 
-        if let Ok(response) = serde_json::from_str::<GeminiResponse>(&text) {
-            Ok(response)
-        } else {
-            Err(serde_json::from_str::<GeminiGenericErrorResponse>(&text)
-                .map(|x| GeminiError::from(x.error))
-                .unwrap_or_else(|x| GeminiError::message(&x.to_string())))
+        let text = response.text().await.map_err(GeminiError::from)?;
+
+        let response: GeminiResponse = parse_response(&text)?;
+        if let Some((cache, key)) = cache.zip(key) {
+            cache.insert(key, response.clone());
         }
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::Span::current();
+            let usage = &response.usage_metadata;
+            span.record("prompt_token_count", usage.prompt_token_count);
+            span.record("candidates_token_count", usage.candidates_token_count);
+            span.record("total_token_count", usage.total_token_count);
+        }
+        Ok(response)
+    }
+
+    /// Generates content for each prompt, running up to `concurrency` requests at a time.
+    ///
+    /// Results are yielded as they complete, so not in order; each is tagged with the
+    /// index of its prompt in `prompts`.
+    pub fn generate_many(
+        &self,
+        prompts: Vec<Vec<Content>>,
+        concurrency: usize,
+    ) -> impl Stream<Item = (usize, Result<GeminiResponse, GeminiError>)> + '_ {
+        stream::iter(prompts.into_iter().enumerate())
+            .map(move |(index, prompt)| async move { (index, self.generate_content(prompt).await) })
+            .buffer_unordered(concurrency.max(1))
+    }
+
+    /// Generates content with a tweaked copy of the model's generation configuration.
+    ///
+    /// Unlike [GenerativeModel::generate_content_with], which replaces the whole
+    /// configuration, this keeps every setting `tweak` doesn't change, e.g.
+    /// `|config| config.temperature = Some(0.2)`.
+    pub async fn generate_content_tweaked(
+        &self,
+        prompt: Vec<Content>,
+        tweak: impl FnOnce(&mut GenerationConfig),
+    ) -> Result<GeminiResponse, GeminiError> {
+        let mut generation_config = self.generation_config.clone().unwrap_or_default();
+        tweak(&mut generation_config);
+        let mut config = GenerativeModelBuilder::new();
+        config.generation_config(generation_config);
+        self.generate_content_with(prompt, config).await
     }
 
     /// Generates a stream of content responses based on the provided prompt, overriding some of the model's configurations using the provided builder.
@@ -171,139 +607,2065 @@ impl GenerativeModel {
         prompt: Vec<Content>,
         config: GenerativeModelBuilder,
     ) -> Result<impl Stream<Item = Result<GeminiResponse, GeminiError>>, GeminiError> {
-        let response = self.send_request(prompt, config, true).await?;
-
-        let stream = response.bytes_stream().filter_map(|chunk| async move {
-            match chunk {
-                Ok(chunk) => {
-                    // we skip either '[' (which happens in the first chunk) or ',' in the subsequent chunks
-                    let str = &str::from_utf8(&chunk)
-                        .expect("Unexpected: this should not happen. Please report this bug to rusty-gemini repo.")[1..];
-
-                    // in the last chunk, str should be empty
-                    if str.is_empty() {
-                        None
-                    } else if let Ok(response) = serde_json::from_str::<GeminiResponse>(&str) {
-                        Some(Ok(response))
-                    } else {
-                        Some(Err(serde_json::from_str::<GeminiGenericErrorResponse>(
-                            &str,
-                        )
-                        .map(|x| GeminiError::from(x.error))
-                        .unwrap_or_else(|err| GeminiError::message(&err.to_string()))))
-                    }
+        let format = config.stream_format.unwrap_or(self.stream_format);
+        let context = config
+            .request_context
+            .clone()
+            .or_else(|| self.request_context.clone());
+        let (model, request) = self
+            .validated_request(prompt, config)
+            .map_err(|err| err.with_context(context.clone()))?;
+        if let Some(on_request) = &self.callbacks.on_request {
+            on_request(&request, context.as_ref());
+        }
+        let on_response = self.callbacks.on_response.clone();
+        let stream = match self.generate_streamed(&model, &request, format).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                if let Some(on_response) = &on_response {
+                    on_response(Err(&err), context.as_ref());
                 }
-                Err(err) => Some(Err(GeminiError::message(&err.to_string()))),
+                return Err(err.with_context(context));
             }
+        };
+        Ok(stream.map(move |chunk| {
+            if let Some(on_response) = &on_response {
+                on_response(chunk.as_ref(), context.as_ref());
+            }
+            chunk.map_err(|err| err.with_context(context.clone()))
+        }))
+    }
+
+    async fn generate_streamed(
+        &self,
+        model: &GeminiModel,
+        request: &GeminiRequest,
+        format: StreamFormat,
+    ) -> Result<impl Stream<Item = Result<GeminiResponse, GeminiError>>, GeminiError> {
+        let response = self.send_request(model, request, Some(format)).await?;
+        // An error is a plain JSON body, which the decoder would find no chunks in.
+        if !response.status().is_success() {
+            let text = response.text().await.map_err(GeminiError::from)?;
+            let error: GeminiGenericErrorResponse = parse_response(&text)?;
+            return Err(error.error.into());
+        }
+
+        let mut decoder = StreamDecoder::new(format);
+        // `None` marks the end of the body, where the decoder is flushed.
+        let chunks = response
+            .bytes_stream()
+            .map(Some)
+            .chain(stream::once(std::future::ready(None)));
+        let stream = chunks.flat_map(move |chunk| {
+            let responses: Vec<_> = match chunk {
+                Some(Ok(chunk)) => decoder
+                    .push(&chunk)
+                    .into_iter()
+                    .map(|object| parse_response(&object?))
+                    .collect(),
+                Some(Err(err)) => vec![Err(GeminiError::from(err))],
+                None => decoder
+                    .finish()
+                    .into_iter()
+                    .map(|object| parse_response(&object?))
+                    .collect(),
+            };
+            stream::iter(responses)
         });
         Ok(stream)
     }
 
+    /// Streams the response to `prompt` from a spawned task, sending each chunk over a
+    /// bounded channel, so chunks can be pulled on demand (e.g. from a TUI event loop).
+    ///
+    /// The task waits while the channel is full, and stops once the receiver is dropped.
+    /// A failure to start the stream is sent as the only item.
+    pub fn generate_content_channel(
+        &self,
+        prompt: Vec<Content>,
+    ) -> (
+        JoinHandle<()>,
+        mpsc::Receiver<Result<GeminiResponse, GeminiError>>,
+    ) {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let model = self.clone();
+        let handle = tokio::spawn(async move {
+            let mut stream = match model.generate_content_streamed(prompt).await {
+                Ok(stream) => pin!(stream),
+                Err(err) => {
+                    let _ = sender.send(Err(err)).await;
+                    return;
+                }
+            };
+            while let Some(chunk) = stream.next().await {
+                if sender.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+        (handle, receiver)
+    }
+
+    /// Streams the response to `prompt` into `writer`, writing and flushing the text
+    /// of each chunk as it arrives (e.g. to print it to stdout).
+    ///
+    /// Returns the usage reported with the last chunk.
+    pub async fn stream_to_writer(
+        &self,
+        prompt: Vec<Content>,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> Result<UsageMetadata, GeminiError> {
+        let io_error = |err: io::Error| GeminiError::message(&err.to_string());
+
+        let mut stream = pin!(self.generate_content_streamed(prompt).await?);
+        let mut usage = UsageMetadata::default();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            if let Some(text) = chunk.candidates.first().and_then(Candidate::text) {
+                writer.write_all(text.as_bytes()).await.map_err(io_error)?;
+                writer.flush().await.map_err(io_error)?;
+            }
+            usage = chunk.usage_metadata;
+        }
+        Ok(usage)
+    }
+
+    /// Returns the compact JSON body [GenerativeModel::generate_content_with] would send
+    /// for `prompt` and `config`, without sending it.
+    ///
+    /// This is a dry run for inspecting the merged configuration, schemas and tools.
+    pub fn build_request_json(
+        &self,
+        prompt: Vec<Content>,
+        config: GenerativeModelBuilder,
+    ) -> Result<String, GeminiError> {
+        let (_, request) = self.validated_request(prompt, config)?;
+        serde_json::to_string(&request).map_err(|err| GeminiError::message(&err.to_string()))
+    }
+
+    /// Like [GenerativeModel::build_request_json], but pretty-printed for reading.
+    pub fn build_request_pretty(
+        &self,
+        prompt: Vec<Content>,
+        config: GenerativeModelBuilder,
+    ) -> Result<String, GeminiError> {
+        let (_, request) = self.validated_request(prompt, config)?;
+        serde_json::to_string_pretty(&request).map_err(|err| GeminiError::message(&err.to_string()))
+    }
+
+    /// Generates a stream of text deltas that survives transient disconnects.
+    ///
+    /// When the stream fails with a retryable error (e.g. a dropped connection), the
+    /// request is sent again, up to `max_restarts` times. The API can't resume a
+    /// generation, so it restarts from the beginning; text that was already yielded
+    /// is skipped, so the caller keeps receiving only new text.
+    pub fn generate_content_streamed_resilient(
+        &self,
+        prompt: Vec<Content>,
+        max_restarts: usize,
+    ) -> impl Stream<Item = Result<String, GeminiError>> + '_ {
+        resilient_text_stream(
+            move || self.generate_content_streamed(prompt.clone()),
+            max_restarts,
+        )
+    }
+
     /// Embeds the content using the model's embedding capabilities.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "gemini.embed_content",
+            skip_all,
+            fields(model = %self.model, latency_ms)
+        )
+    )]
     pub async fn embed_content(
         &self,
         content: impl Into<Content>,
         config: EmbedContentConfig,
     ) -> Result<EmbedContentResponse, GeminiError> {
+        self.model.validate_embedding(&config)?;
         let content = content.into();
         let request = EmbedContentRequest { content, config };
 
-        let client = reqwest::Client::new();
-        let response = client
-            .post(format!(
-                "{BASE_URL}/models/{}:embedContent?key={}",
-                self.model, self.api_key
-            ))
-            .json(&request)
-            .send()
-            .await
-            .map_err(|err| GeminiError::message(&err.to_string()))?;
+        #[cfg(feature = "tracing")]
+        let started = std::time::Instant::now();
+        let text = self.post(&self.model, "embedContent", &request).await?;
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("latency_ms", started.elapsed().as_millis() as u64);
+        parse_response(&text)
+    }
 
-        let text = response
-            .text()
-            .await
-            .map_err(|err| GeminiError::message(&err.to_string()))?;
-        if let Ok(response) = serde_json::from_str::<EmbedContentResponse>(&text) {
-            Ok(response)
-        } else {
-            Err(serde_json::from_str::<GeminiGenericErrorResponse>(&text)
-                .map(|x| GeminiError::from(x.error))
-                .unwrap_or_else(|x| GeminiError::message(&x.to_string())))
+    /// Embeds a search query for retrieval, using the `RETRIEVAL_QUERY` task type.
+    pub async fn embed_query(&self, text: &str) -> Result<ContentEmbedding, GeminiError> {
+        let config = EmbedContentConfig {
+            task_type: Some(TaskType::RetrievalQuery),
+            ..Default::default()
+        };
+        Ok(self.embed_content(text, config).await?.embedding)
+    }
+
+    /// Embeds a document for retrieval, using the `RETRIEVAL_DOCUMENT` task type and its title.
+    pub async fn embed_document(
+        &self,
+        text: &str,
+        title: &str,
+    ) -> Result<ContentEmbedding, GeminiError> {
+        let config = EmbedContentConfig {
+            task_type: Some(TaskType::RetrievalDocument),
+            title: Some(title.to_string()),
+            ..Default::default()
+        };
+        Ok(self.embed_content(text, config).await?.embedding)
+    }
+
+    /// Embeds several contents in one request, each with its own configuration.
+    ///
+    /// Giving each item its own config lets e.g. every document carry its own title.
+    /// The embeddings are returned in the same order as the items.
+    pub async fn batch_embed_content(
+        &self,
+        items: Vec<(Content, EmbedContentConfig)>,
+    ) -> Result<BatchEmbedContentsResponse, GeminiError> {
+        for (_, config) in &items {
+            self.model.validate_embedding(config)?;
         }
+        let request = self.build_batch_embed_request(items);
+
+        let text = self
+            .post(&self.model, "batchEmbedContents", &request)
+            .await?;
+        parse_response(&text)
     }
 
-    async fn send_request(
+    /// Ranks `documents` by how similar they are to `query`, most similar first.
+    ///
+    /// The query and documents are embedded in one batch request (as `RETRIEVAL_QUERY`
+    /// and `RETRIEVAL_DOCUMENT` respectively) and compared by cosine similarity.
+    /// Returns each document's index in `documents` with its similarity.
+    pub async fn rank_by_similarity(
+        &self,
+        query: &str,
+        documents: Vec<String>,
+    ) -> Result<Vec<(usize, f64)>, GeminiError> {
+        let config = |task_type| EmbedContentConfig {
+            task_type: Some(task_type),
+            ..Default::default()
+        };
+        let count = documents.len();
+        let items = std::iter::once((query.into(), config(TaskType::RetrievalQuery)))
+            .chain(
+                documents
+                    .into_iter()
+                    .map(|document| (document.into(), config(TaskType::RetrievalDocument))),
+            )
+            .collect();
+
+        let response = self.batch_embed_content(items).await?;
+        let Some((query, documents)) = response.embeddings.split_first() else {
+            return Err(GeminiError::message("the response has no embeddings"));
+        };
+        if documents.len() != count {
+            return Err(GeminiError::message(&format!(
+                "expected {} embeddings, got {}",
+                count + 1,
+                documents.len() + 1
+            )));
+        }
+
+        let mut ranking: Vec<_> = documents
+            .iter()
+            .map(|document| cosine_similarity(&query.values, &document.values))
+            .enumerate()
+            .collect();
+        ranking.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(ranking)
+    }
+
+    /// Sends `body` to `generateContent` as is and returns the raw JSON response.
+    ///
+    /// This bypasses the typed request and response structs entirely, as an escape
+    /// hatch for API features this crate doesn't model yet.
+    pub async fn generate_content_raw_json(
+        &self,
+        body: serde_json::Value,
+    ) -> Result<serde_json::Value, GeminiError> {
+        let text = self.post(&self.model, "generateContent", &body).await?;
+        if let Ok(error) = serde_json::from_str::<GeminiGenericErrorResponse>(&text) {
+            return Err(error.error.into());
+        }
+        parse_response(&text)
+    }
+
+    /// Generates content based on the provided prompt, using the given tools instead of the model's tools for this call only.
+    pub async fn generate_content_with_tools(
         &self,
         prompt: Vec<Content>,
-        config: GenerativeModelBuilder,
-        stream: bool,
+        tools: Vec<Tool>,
+    ) -> Result<GeminiResponse, GeminiError> {
+        let mut config = GenerativeModelBuilder::new();
+        config.tools = Some(tools);
+        self.generate_content_with(prompt, config).await
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "gemini.send_request",
+            skip_all,
+            fields(model = %model, streamed = stream.is_some(), status, latency_ms)
+        )
+    )]
+    async fn send_request(
+        &self,
+        model: &GeminiModel,
+        request: &GeminiRequest,
+        stream: Option<StreamFormat>,
     ) -> Result<reqwest::Response, GeminiError> {
-        let request = GeminiRequest {
-            contents: prompt,
-            tools: config.tools.or_else(|| self.tools.clone()),
-            safety_settings: config
-                .safety_settings
-                .or_else(|| self.safety_settings.clone()),
-            system_instruction: config
-                .system_instruction
-                .or_else(|| self.system_instruction.clone()),
-            generation_config: config
-                .generation_config
-                .or_else(|| self.generation_config.clone()),
+        let method = match stream {
+            Some(_) => "streamGenerateContent",
+            None => "generateContent",
         };
-        let client = reqwest::Client::new();
-        let suffix = if stream {
-            "streamGenerateContent"
-        } else {
-            "generateContent"
+        let query: &[(&str, &str)] = match stream {
+            Some(StreamFormat::Sse) => &[("alt", "sse")],
+            _ => &[],
         };
-        let response = client
-            .post(format!(
-                "{BASE_URL}/models/{}:{}?key={}",
-                config.model.as_ref().unwrap_or(&self.model),
-                suffix,
-                self.api_key
-            ))
-            .json(&request)
+        #[cfg(feature = "tracing")]
+        let started = std::time::Instant::now();
+        let response = self
+            .authorized(Method::POST, self.url(model, method))
+            .query(query)
+            .json(request)
             .send()
             .await
-            .map_err(|err| GeminiError {
-                kind: GeminiErrorKind::Other,
-                message: err.to_string(),
-            })?;
+            .map_err(GeminiError::from)?;
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::Span::current();
+            span.record("status", response.status().as_u16());
+            span.record("latency_ms", started.elapsed().as_millis() as u64);
+        }
         Ok(response)
     }
-}
 
-/// Represents the different Gemini models available.
-#[derive(Debug, Default, Clone)]
-#[allow(non_camel_case_types)]
-pub enum GeminiModel {
-    /// The Gemini 1.5 Pro model.
-    #[default]
-    Pro_1_5,
-    /// The Gemini 1.5 Flash model.
-    Flash_1_5,
-    /// The Gemini 1.5 Flash 8B model.
-    Flash_1_5_8B,
-    /// The Text Embedding 004 model.
-    TextEmbedding004,
-    /// A custom Gemini model specified by its name.
-    Custom(Cow<'static, str>),
-}
+    /// Warns, or errors if [GenerativeModel::strict_tool_check] is set, when `request`
+    /// has tools but `model` is known not to support them. Returns whether it warned.
+    fn check_tool_support(
+        &self,
+        model: &GeminiModel,
+        request: &GeminiRequest,
+    ) -> Result<bool, GeminiError> {
+        let has_tools = request
+            .tools
+            .as_ref()
+            .is_some_and(|tools| !tools.is_empty());
+        if !has_tools || model.supports_tools() != Some(false) {
+            return Ok(false);
+        }
+        let message = format!("{model} does not support tools");
+        if self.strict_tool_check {
+            return Err(GeminiError::invalid_argument(&message));
+        }
+        if model.is_gemma() {
+            log::warn!("{message}; they were left out of the request");
+        } else {
+            log::warn!("{message}; the request will likely be rejected");
+        }
+        Ok(true)
+    }
 
-impl Display for GeminiModel {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                GeminiModel::Pro_1_5 => "gemini-1.5-pro",
-                GeminiModel::Flash_1_5 => "gemini-1.5-flash",
-                GeminiModel::Flash_1_5_8B => "gemini-1.5-flash-8b",
-                GeminiModel::TextEmbedding004 => "text-embedding-004",
-                GeminiModel::Custom(custom) => custom,
-            }
-        )
+    /// Builds the request for `prompt` and the model it is sent to, checking it for
+    /// mistakes the API would reject.
+    fn validated_request(
+        &self,
+        prompt: Vec<Content>,
+        config: GenerativeModelBuilder,
+    ) -> Result<(GeminiModel, GeminiRequest), GeminiError> {
+        let model = config.model.clone().unwrap_or_else(|| self.model.clone());
+        model.validate_mime_types(&prompt)?;
+        let mut request = self.build_request(prompt, config);
+        // Before the Gemma adaptation, which drops the tools.
+        let tools_reported = self.check_tool_support(&model, &request)?;
+        if model.is_gemma() {
+            adapt_for_gemma(&mut request, tools_reported);
+        }
+        if let Some(generation_config) = &request.generation_config {
+            generation_config.validate()?;
+        }
+        if let Some(safety_settings) = &request.safety_settings {
+            validate_safety_settings(safety_settings)?;
+        }
+        Ok((model, request))
+    }
+
+    /// Posts `body` to `method` of `model`, returning the response body.
+    async fn post<B: Serialize>(
+        &self,
+        model: &GeminiModel,
+        method: &str,
+        body: &B,
+    ) -> Result<String, GeminiError> {
+        let response = self
+            .authorized(Method::POST, self.url(model, method))
+            .json(body)
+            .send()
+            .await
+            .map_err(GeminiError::from)?;
+
+        response.text().await.map_err(GeminiError::from)
+    }
+
+    /// Starts a request to `url`, authenticated with the API key.
+    pub(crate) fn authorized(&self, method: Method, url: String) -> reqwest::RequestBuilder {
+        if self.auth_via_header {
+            self.client
+                .request(method, url)
+                .header("x-goog-api-key", &self.api_key)
+        } else {
+            self.client
+                .request(method, url)
+                .query(&[("key", &self.api_key)])
+        }
+    }
+
+    /// The URL of `method` (e.g. `generateContent`) for `model`.
+    pub(crate) fn url(&self, model: &GeminiModel, method: &str) -> String {
+        format!("{}/models/{}:{}", self.base_url, model, method)
+    }
+
+    fn build_batch_embed_request(
+        &self,
+        items: Vec<(Content, EmbedContentConfig)>,
+    ) -> BatchEmbedContentsRequest {
+        BatchEmbedContentsRequest {
+            requests: items
+                .into_iter()
+                .map(|(content, config)| BatchEmbedContentsItem {
+                    model: format!("models/{}", self.model),
+                    request: EmbedContentRequest { content, config },
+                })
+                .collect(),
+        }
+    }
+
+    /// Builds the request body, preferring the values set on `config` over the model's own.
+    fn build_request(&self, prompt: Vec<Content>, config: GenerativeModelBuilder) -> GeminiRequest {
+        let config = self.effective_config(&config);
+        GeminiRequest {
+            contents: prompt,
+            tools: config.tools,
+            safety_settings: config.safety_settings,
+            system_instruction: config.system_instruction,
+            generation_config: config.generation_config,
+            labels: config.labels,
+        }
+    }
+
+    /// The configuration a call with `overrides` (e.g. [GenerativeModel::generate_content_with])
+    /// would use.
+    ///
+    /// Each setting given in `overrides` replaces the model's setting as a whole; for
+    /// example, overriding the generation config drops the model's temperature even if
+    /// the override doesn't set one.
+    pub fn effective_config(&self, overrides: &GenerativeModelBuilder) -> EffectiveConfig {
+        fn pick<T: Clone>(over: &Option<T>, default: &Option<T>) -> Option<T> {
+            over.as_ref().or(default.as_ref()).cloned()
+        }
+        EffectiveConfig {
+            model: overrides
+                .model
+                .clone()
+                .unwrap_or_else(|| self.model.clone()),
+            generation_config: pick(&overrides.generation_config, &self.generation_config),
+            tools: pick(&overrides.tools, &self.tools),
+            safety_settings: pick(&overrides.safety_settings, &self.safety_settings),
+            system_instruction: pick(&overrides.system_instruction, &self.system_instruction),
+            labels: pick(&overrides.labels, &self.labels).filter(|labels| !labels.is_empty()),
+        }
+    }
+}
+
+/// The configuration used for a request, after merging per-call overrides into the
+/// model's defaults. See [GenerativeModel::effective_config].
+#[derive(Debug, Clone)]
+pub struct EffectiveConfig {
+    pub model: GeminiModel,
+    pub generation_config: Option<GenerationConfig>,
+    pub tools: Option<Vec<Tool>>,
+    pub safety_settings: Option<Vec<SafetySetting>>,
+    pub system_instruction: Option<Content>,
+    pub labels: Option<HashMap<String, String>>,
+}
+
+/// Adjusts `request` for the Gemma models, which the API serves with fewer features.
+///
+/// Gemma has no system instructions, so the system instruction is prepended to the
+/// first user turn instead, and it can't call tools, so tools are dropped (with a
+/// warning, unless `tools_reported` says one was already logged).
+fn adapt_for_gemma(request: &mut GeminiRequest, tools_reported: bool) {
+    if let Some(instruction) = request.system_instruction.take() {
+        match request.contents.first_mut() {
+            Some(first) if first.role == Role::User => {
+                first.parts.splice(..0, instruction.parts);
+            }
+            _ => request.contents.insert(
+                0,
+                Content {
+                    role: Role::User,
+                    parts: instruction.parts,
+                },
+            ),
+        }
+    }
+    if request.tools.take().is_some() && !tools_reported {
+        log::warn!("Gemma models don't support tools; they were left out of the request");
+    }
+}
+
+/// The instruction sent after a response was cut off by `MAX_TOKENS`.
+const CONTINUE_INSTRUCTION: &str =
+    "Continue exactly where you left off, without repeating anything you already wrote.";
+
+async fn continue_generation<F, Fut>(
+    mut contents: Vec<Content>,
+    max_rounds: usize,
+    mut generate: F,
+) -> Result<String, GeminiError>
+where
+    F: FnMut(Vec<Content>) -> Fut,
+    Fut: Future<Output = Result<GeminiResponse, GeminiError>>,
+{
+    if max_rounds == 0 {
+        return Err(GeminiError::invalid_argument(
+            "max_rounds must be greater than zero",
+        ));
+    }
+    let mut text = String::new();
+    for _ in 0..max_rounds {
+        let response = generate(contents.clone()).await?;
+        let candidate = response
+            .candidates
+            .into_iter()
+            .next()
+            .ok_or_else(|| GeminiError::message("the response has no candidates"))?;
+        text.push_str(&candidate.text().unwrap_or_default());
+
+        if !matches!(candidate.finish_reason, Some(FinishReason::MaxTokens)) {
+            break;
+        }
+        contents.push(candidate.content);
+        contents.push(Content::user(CONTINUE_INSTRUCTION));
+    }
+    Ok(text)
+}
+
+/// Parses a successful response body as `T`, or the API's error body as a [GeminiError].
+///
+/// If the body is neither, the error message includes the start of the raw body.
+pub(crate) fn parse_response<T: DeserializeOwned>(text: &str) -> Result<T, GeminiError> {
+    /// How much of an unparseable body is included in the error message.
+    const MAX_BODY_CHARS: usize = 500;
+
+    let err = match serde_json::from_str::<T>(text) {
+        Ok(response) => return Ok(response),
+        Err(err) => err,
+    };
+    Err(serde_json::from_str::<GeminiGenericErrorResponse>(text)
+        .map(|x| GeminiError::from(x.error))
+        .unwrap_or_else(|_| {
+            let mut body: String = text.chars().take(MAX_BODY_CHARS).collect();
+            if body.len() < text.len() {
+                body.push_str("...");
+            }
+            GeminiError::message(&format!(
+                "failed to parse the response: {err}; response body: {body}"
+            ))
+        }))
+}
+
+/// Represents the different Gemini models available.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+#[allow(non_camel_case_types)]
+pub enum GeminiModel {
+    /// The Gemini 1.5 Pro model.
+    #[default]
+    Pro_1_5,
+    /// The Gemini 1.5 Flash model.
+    Flash_1_5,
+    /// The Gemini 1.5 Flash 8B model.
+    Flash_1_5_8B,
+    /// The Text Embedding 004 model.
+    TextEmbedding004,
+    /// The attributed question answering model, which answers from the passages it's given.
+    Aqa,
+    /// The instruction-tuned Gemma 2 2B model.
+    ///
+    /// Like every Gemma model, it has no system instructions or tools: the system
+    /// instruction is sent at the start of the first user turn and tools are left out.
+    Gemma2_2B,
+    /// The instruction-tuned Gemma 2 9B model; see [GeminiModel::Gemma2_2B] for its limitations.
+    Gemma2_9B,
+    /// The instruction-tuned Gemma 2 27B model; see [GeminiModel::Gemma2_2B] for its limitations.
+    Gemma2_27B,
+    /// A custom Gemini model specified by its name.
+    Custom(Cow<'static, str>),
+}
+
+/// MIME types accepted as inline data by the Gemini 1.5 models.
+const GEMINI_1_5_MIME_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/webp",
+    "image/heic",
+    "image/heif",
+    "audio/wav",
+    "audio/mp3",
+    "audio/aiff",
+    "audio/aac",
+    "audio/ogg",
+    "audio/flac",
+    "video/mp4",
+    "video/mpeg",
+    "video/mov",
+    "video/avi",
+    "video/x-flv",
+    "video/mpg",
+    "video/webm",
+    "video/wmv",
+    "video/3gpp",
+    "application/pdf",
+    "application/json",
+    "application/rtf",
+    "application/x-javascript",
+    "application/x-typescript",
+    "application/x-python-code",
+    "text/plain",
+    "text/html",
+    "text/css",
+    "text/csv",
+    "text/markdown",
+    "text/javascript",
+    "text/x-typescript",
+    "text/x-python",
+    "text/xml",
+    "text/rtf",
+];
+
+impl GeminiModel {
+    /// The MIME types this model accepts as inline data, or `None` if unknown.
+    fn supported_mime_types(&self) -> Option<&'static [&'static str]> {
+        match self {
+            GeminiModel::Pro_1_5 | GeminiModel::Flash_1_5 | GeminiModel::Flash_1_5_8B => {
+                Some(GEMINI_1_5_MIME_TYPES)
+            }
+            GeminiModel::TextEmbedding004
+            | GeminiModel::Aqa
+            | GeminiModel::Gemma2_2B
+            | GeminiModel::Gemma2_9B
+            | GeminiModel::Gemma2_27B => Some(&[]),
+            GeminiModel::Custom(_) => None,
+        }
+    }
+
+    /// Checks that every inline data part in `contents` has a MIME type this model accepts.
+    ///
+    /// Custom models have no known list, so unrecognized types only log a warning.
+    fn validate_mime_types(&self, contents: &[Content]) -> Result<(), GeminiError> {
+        for content in contents {
+            for (mime_type, _) in content.data_parts() {
+                match self.supported_mime_types() {
+                    Some(supported) if !supported.contains(&mime_type) => {
+                        return Err(GeminiError::invalid_argument(&format!(
+                            "{self} does not support inline data of type {mime_type}"
+                        )));
+                    }
+                    None if !GEMINI_1_5_MIME_TYPES.contains(&mime_type) => {
+                        log::warn!("{self} may not support inline data of type {mime_type}");
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether this is one of the open Gemma models.
+    fn is_gemma(&self) -> bool {
+        matches!(
+            self,
+            GeminiModel::Gemma2_2B | GeminiModel::Gemma2_9B | GeminiModel::Gemma2_27B
+        )
+    }
+
+    /// The maximum number of input tokens this model accepts, or `None` if unknown.
+    pub fn input_token_limit(&self) -> Option<usize> {
+        match self {
+            GeminiModel::Pro_1_5 => Some(2_097_152),
+            GeminiModel::Flash_1_5 | GeminiModel::Flash_1_5_8B => Some(1_048_576),
+            GeminiModel::TextEmbedding004 => Some(2_048),
+            GeminiModel::Aqa => Some(7_168),
+            GeminiModel::Gemma2_2B | GeminiModel::Gemma2_9B | GeminiModel::Gemma2_27B => {
+                Some(8_192)
+            }
+            GeminiModel::Custom(_) => None,
+        }
+    }
+
+    /// The `output_dimensionality` values this model accepts, or `None` if unknown.
+    fn supported_dimensionality(&self) -> Option<RangeInclusive<i32>> {
+        match self {
+            GeminiModel::TextEmbedding004 => Some(1..=768),
+            _ => None,
+        }
+    }
+
+    /// Whether this model computes embeddings, or `None` if unknown.
+    fn supports_embedding(&self) -> Option<bool> {
+        match self {
+            GeminiModel::Pro_1_5
+            | GeminiModel::Flash_1_5
+            | GeminiModel::Flash_1_5_8B
+            | GeminiModel::Aqa
+            | GeminiModel::Gemma2_2B
+            | GeminiModel::Gemma2_9B
+            | GeminiModel::Gemma2_27B => Some(false),
+            GeminiModel::TextEmbedding004 => Some(true),
+            GeminiModel::Custom(_) => None,
+        }
+    }
+
+    /// Whether this model supports tools such as function calling, or `None` if unknown.
+    fn supports_tools(&self) -> Option<bool> {
+        match self {
+            GeminiModel::Pro_1_5 | GeminiModel::Flash_1_5 | GeminiModel::Flash_1_5_8B => Some(true),
+            GeminiModel::Aqa
+            | GeminiModel::Gemma2_2B
+            | GeminiModel::Gemma2_9B
+            | GeminiModel::Gemma2_27B
+            | GeminiModel::TextEmbedding004 => Some(false),
+            GeminiModel::Custom(_) => None,
+        }
+    }
+
+    /// Checks that this is an embedding model and that `config` doesn't ask for an
+    /// embedding size it can't produce.
+    fn validate_embedding(&self, config: &EmbedContentConfig) -> Result<(), GeminiError> {
+        if self.supports_embedding() == Some(false) {
+            return Err(GeminiError::invalid_argument(&format!(
+                "{self} is not an embedding model; use an embedding model such as {}",
+                GeminiModel::TextEmbedding004
+            )));
+        }
+        let Some(dimensionality) = config.output_dimensionality else {
+            return Ok(());
+        };
+        let supported = self.supported_dimensionality();
+        match supported {
+            Some(range) if !range.contains(&dimensionality) => {
+                Err(GeminiError::invalid_argument(&format!(
+                    "{self} supports output_dimensionality from {} to {}, got {dimensionality}",
+                    range.start(),
+                    range.end()
+                )))
+            }
+            None if dimensionality < 1 => Err(GeminiError::invalid_argument(&format!(
+                "output_dimensionality must be positive, got {dimensionality}"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn known(name: &str) -> Option<Self> {
+        match name {
+            "gemini-1.5-pro" => Some(GeminiModel::Pro_1_5),
+            "gemini-1.5-flash" => Some(GeminiModel::Flash_1_5),
+            "gemini-1.5-flash-8b" => Some(GeminiModel::Flash_1_5_8B),
+            "text-embedding-004" => Some(GeminiModel::TextEmbedding004),
+            "aqa" => Some(GeminiModel::Aqa),
+            "gemma-2-2b-it" => Some(GeminiModel::Gemma2_2B),
+            "gemma-2-9b-it" => Some(GeminiModel::Gemma2_9B),
+            "gemma-2-27b-it" => Some(GeminiModel::Gemma2_27B),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for GeminiModel {
+    /// Maps a known model name to its variant, and any other name to [GeminiModel::Custom].
+    fn from(value: &str) -> Self {
+        GeminiModel::known(value).unwrap_or_else(|| GeminiModel::Custom(value.to_string().into()))
+    }
+}
+
+impl From<String> for GeminiModel {
+    /// Maps a known model name to its variant, and any other name to [GeminiModel::Custom].
+    fn from(value: String) -> Self {
+        GeminiModel::known(&value).unwrap_or_else(|| GeminiModel::Custom(value.into()))
+    }
+}
+
+impl Display for GeminiModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                GeminiModel::Pro_1_5 => "gemini-1.5-pro",
+                GeminiModel::Flash_1_5 => "gemini-1.5-flash",
+                GeminiModel::Flash_1_5_8B => "gemini-1.5-flash-8b",
+                GeminiModel::TextEmbedding004 => "text-embedding-004",
+                GeminiModel::Aqa => "aqa",
+                GeminiModel::Gemma2_2B => "gemma-2-2b-it",
+                GeminiModel::Gemma2_9B => "gemma-2-9b-it",
+                GeminiModel::Gemma2_27B => "gemma-2-27b-it",
+                GeminiModel::Custom(custom) => custom,
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::FunctionDeclaration,
+        content::Part,
+        grounding::{DynamicRetrievalConfig, GoogleSearchRetrieval, Mode},
+        mock::MockServer,
+        pricing::Pricing,
+    };
+
+    fn function_tool(name: &str) -> Tool {
+        Tool {
+            function_declarations: Some(vec![FunctionDeclaration {
+                name: name.to_string(),
+                description: String::new(),
+                parameters: None,
+            }]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn estimate_cost_prices_counted_tokens() {
+        let server = MockServer::ok(serde_json::json!({ "totalTokens": 200000 })).await;
+        let mut prices = PriceTable::empty();
+        prices.set(GeminiModel::Flash_1_5, Pricing::new(0.5, 2.0));
+        let model = server
+            .model()
+            .model(GeminiModel::Flash_1_5)
+            .price_table(prices)
+            .build();
+
+        let estimate = model
+            .estimate_cost(vec!["a long prompt".into()], 1000)
+            .await
+            .unwrap();
+        assert_eq!(estimate.input_tokens, 200000);
+        assert!((estimate.input_cost - 0.1).abs() < 1e-12);
+        assert!((estimate.output_cost - 0.002).abs() < 1e-12);
+        assert!((estimate.total_cost - 0.102).abs() < 1e-12);
+        let request = &server.requests()[0];
+        assert!(request
+            .path
+            .ends_with("/models/gemini-1.5-flash:countTokens"));
+        assert_eq!(
+            request.json()["contents"][0]["parts"][0]["text"],
+            "a long prompt"
+        );
+
+        let unpriced = model.clone_with_model(GeminiModel::Aqa);
+        let err = unpriced
+            .estimate_cost(vec!["hi".into()], 10)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::InvalidArgument));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn generate_many_tags_results_with_their_index() {
+        let server = MockServer::ok(serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": "ok" }] } }],
+            "usageMetadata": {}
+        }))
+        .await;
+        let model = server.model().build();
+        let prompts = (0..5)
+            .map(|i| vec![Content::user(format!("prompt {i}"))])
+            .collect();
+
+        let mut indices: Vec<usize> = model
+            .generate_many(prompts, 2)
+            .map(|(index, result)| {
+                assert_eq!(result.unwrap().text().as_deref(), Some("ok"));
+                index
+            })
+            .collect()
+            .await;
+        indices.sort();
+        assert_eq!(indices, [0, 1, 2, 3, 4]);
+
+        let mut sent: Vec<String> = server
+            .requests()
+            .iter()
+            .map(|request| request.json()["contents"][0]["parts"][0]["text"].to_string())
+            .collect();
+        sent.sort();
+        assert_eq!(sent.len(), 5);
+        assert_eq!(sent[4], "\"prompt 4\"");
+    }
+
+    #[test]
+    fn display_names_are_not_sent() {
+        let model = GenerativeModelBuilder::new().api_key("key").build();
+        let prompt = Content::user("Describe the chart.").with_part(Part::Data {
+            data: vec![1, 2, 3],
+            mime_type: "image/png".to_string(),
+            display_name: Some("chart".to_string()),
+        });
+
+        let json = model
+            .build_request_json(vec![prompt], GenerativeModelBuilder::new())
+            .unwrap();
+        let request: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let blob = &request["contents"][0]["parts"][1]["inlineData"];
+        assert_eq!(blob["mimeType"], "image/png");
+        assert!(blob.get("displayName").is_none());
+    }
+
+    #[tokio::test]
+    async fn cache_hit_skips_the_network() {
+        let server = MockServer::ok(serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": "4" }] } }],
+            "usageMetadata": {}
+        }))
+        .await;
+        let model = server
+            .model()
+            .generation_config(GenerationConfig {
+                temperature: Some(0.0),
+                ..Default::default()
+            })
+            .response_cache(ResponseCache::new(8))
+            .build();
+
+        for _ in 0..2 {
+            let response = model.generate_content(vec!["2+2?".into()]).await.unwrap();
+            assert_eq!(response.text().as_deref(), Some("4"));
+        }
+        assert_eq!(server.requests().len(), 1);
+
+        // A different prompt, or a request that isn't deterministic, isn't a hit.
+        model.generate_content(vec!["3+3?".into()]).await.unwrap();
+        let mut creative = GenerativeModelBuilder::new();
+        creative.generation_config = Some(GenerationConfig {
+            temperature: Some(1.0),
+            ..Default::default()
+        });
+        model
+            .generate_content_with(vec!["2+2?".into()], creative.clone())
+            .await
+            .unwrap();
+        model
+            .generate_content_with(vec!["2+2?".into()], creative)
+            .await
+            .unwrap();
+        assert_eq!(server.requests().len(), 4);
+        assert_eq!(model.response_cache.as_ref().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn gemma_requests_omit_unsupported_fields() {
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .model(GeminiModel::Gemma2_9B)
+            .system_instruction("Answer in French.")
+            .tool(function_tool("lookup"))
+            .build();
+
+        let json = model
+            .build_request_json(vec!["Hello".into()], GenerativeModelBuilder::new())
+            .unwrap();
+        let request: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(request.get("system_instruction").is_none());
+        assert!(request.get("tools").is_none());
+        let parts = &request["contents"][0]["parts"];
+        assert_eq!(parts[0]["text"], "Answer in French.");
+        assert_eq!(parts[1]["text"], "Hello");
+
+        let gemini = model.clone_with_model(GeminiModel::Flash_1_5);
+        let json = gemini
+            .build_request_json(vec!["Hello".into()], GenerativeModelBuilder::new())
+            .unwrap();
+        let request: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(request.get("system_instruction").is_some());
+        assert!(request.get("tools").is_some());
+    }
+
+    #[test]
+    fn effective_config_merges_overrides() {
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .model(GeminiModel::Flash_1_5)
+            .system_instruction("Be brief.")
+            .generation_config(GenerationConfig {
+                temperature: Some(0.2),
+                ..Default::default()
+            })
+            .tool(function_tool("model_tool"))
+            .build();
+
+        let mut overrides = GenerativeModelBuilder::new();
+        overrides.tools = Some(vec![function_tool("call_tool")]);
+        overrides.generation_config = Some(GenerationConfig {
+            top_k: Some(5),
+            ..Default::default()
+        });
+        let config = model.effective_config(&overrides);
+
+        assert_eq!(config.model, GeminiModel::Flash_1_5);
+        assert_eq!(
+            config.system_instruction.unwrap().text_parts(),
+            vec!["Be brief."]
+        );
+        let tools = serde_json::to_value(config.tools).unwrap();
+        assert_eq!(tools[0]["functionDeclarations"][0]["name"], "call_tool");
+        let generation_config = config.generation_config.unwrap();
+        assert_eq!(generation_config.top_k, Some(5));
+        assert_eq!(generation_config.temperature, None);
+        assert!(config.safety_settings.is_none());
+    }
+
+    #[test]
+    fn per_call_tools_override_model_tools() {
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .tool(function_tool("model_tool"))
+            .build();
+
+        let mut config = GenerativeModelBuilder::new();
+        config.tools = Some(vec![function_tool("call_tool")]);
+        let request = model.build_request(vec!["hi".into()], config);
+
+        let tools = serde_json::to_value(request.tools).unwrap();
+        assert_eq!(tools[0]["functionDeclarations"][0]["name"], "call_tool");
+        assert_eq!(tools.as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn tools_of_different_kinds_are_separate_entries() {
+        let search = Tool {
+            google_search_retrieval: Some(GoogleSearchRetrieval {
+                dynamic_retrieval_config: DynamicRetrievalConfig {
+                    mode: Mode::ModeDynamic,
+                    dynamic_threshold: None,
+                },
+            }),
+            ..Default::default()
+        };
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .tool(function_tool("lookup"))
+            .tool(search)
+            .build();
+
+        let request = model.build_request(vec!["hi".into()], GenerativeModelBuilder::new());
+        let tools = serde_json::to_value(request.tools).unwrap();
+
+        assert_eq!(tools.as_array().unwrap().len(), 2);
+        assert_eq!(tools[0]["functionDeclarations"][0]["name"], "lookup");
+        assert!(tools[0].get("googleSearchRetrieval").is_none());
+        assert!(tools[1]["googleSearchRetrieval"]["dynamicRetrievalConfig"].is_object());
+        assert!(tools[1].get("functionDeclarations").is_none());
+    }
+
+    #[test]
+    fn batch_embed_items_keep_their_own_config() {
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .model(GeminiModel::TextEmbedding004)
+            .build();
+        let document = |title: &str| EmbedContentConfig {
+            task_type: Some(TaskType::RetrievalDocument),
+            title: Some(title.to_string()),
+            ..Default::default()
+        };
+
+        let request = model.build_batch_embed_request(vec![
+            ("first body".into(), document("First")),
+            ("second body".into(), document("Second")),
+        ]);
+        let json = serde_json::to_value(&request).unwrap();
+
+        let requests = json["requests"].as_array().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0]["model"], "models/text-embedding-004");
+        assert_eq!(requests[0]["title"], "First");
+        assert_eq!(requests[1]["title"], "Second");
+        assert_eq!(requests[1]["taskType"], "RETRIEVAL_DOCUMENT");
+        assert_eq!(requests[1]["content"]["parts"][0]["text"], "second body");
+    }
+
+    #[test]
+    fn clone_with_model_only_swaps_model() {
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .model(GeminiModel::Pro_1_5)
+            .system_instruction("be terse")
+            .generation_config(GenerationConfig::deterministic())
+            .tool(function_tool("lookup"))
+            .build();
+        let clone = model.clone_with_model(GeminiModel::Flash_1_5);
+
+        assert!(matches!(clone.model, GeminiModel::Flash_1_5));
+        assert!(matches!(model.model, GeminiModel::Pro_1_5));
+        assert_eq!(clone.api_key, model.api_key);
+        let config = |m: &GenerativeModel| {
+            format!(
+                "{:?} {:?} {:?} {:?}",
+                m.generation_config, m.system_instruction, m.safety_settings, m.tools
+            )
+        };
+        assert_eq!(config(&clone), config(&model));
+    }
+
+    #[test]
+    fn unsafe_disable_all_safety_covers_every_category() {
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .safety_setting(SafetySetting {
+                category: HarmCategory::Harassment,
+                threshold: HarmBlockThreshold::Low,
+            })
+            .unsafe_disable_all_safety()
+            .build();
+
+        let settings = serde_json::to_value(model.safety_settings.unwrap()).unwrap();
+        let categories: Vec<_> = settings
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|setting| {
+                assert_eq!(setting["threshold"], "BLOCK_NONE");
+                setting["category"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(
+            categories,
+            [
+                "HARM_CATEGORY_HARASSMENT",
+                "HARM_CATEGORY_HATE_SPEECH",
+                "HARM_CATEGORY_SEXUALLY_EXPLICIT",
+                "HARM_CATEGORY_DANGEROUS_CONTENT",
+                "HARM_CATEGORY_CIVIC_INTEGRITY",
+            ]
+        );
+    }
+
+    #[test]
+    fn candidate_count_bounds() {
+        let build = |count| {
+            GenerativeModelBuilder::new()
+                .api_key("key")
+                .candidate_count(count)
+                .try_build()
+        };
+
+        for count in [1, 8] {
+            let model = build(count).unwrap();
+            assert_eq!(
+                model.generation_config.unwrap().candidate_count,
+                Some(count)
+            );
+        }
+        for count in [0, 9] {
+            let err = build(count).unwrap_err();
+            assert!(matches!(err.kind, GeminiErrorKind::InvalidArgument));
+        }
+    }
+
+    #[test]
+    fn unsupported_mime_type_is_rejected() {
+        let prompt = vec![Content::user("describe this").with_part(Part::Data {
+            data: vec![0x49, 0x49, 0x2a, 0x00],
+            mime_type: "image/tiff".to_string(),
+            display_name: None,
+        })];
+
+        let err = GeminiModel::Flash_1_5
+            .validate_mime_types(&prompt)
+            .unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::InvalidArgument));
+        assert!(err.message.contains("image/tiff"));
+
+        assert!(GeminiModel::Custom("my-model".into())
+            .validate_mime_types(&prompt)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn duplicate_safety_categories_are_rejected() {
+        let server = MockServer::ok(serde_json::json!({})).await;
+        let model = server
+            .model()
+            .safety_setting(SafetySetting {
+                category: HarmCategory::Harassment,
+                threshold: HarmBlockThreshold::Low,
+            })
+            .safety_setting(SafetySetting {
+                category: HarmCategory::Harassment,
+                threshold: HarmBlockThreshold::None,
+            })
+            .build();
+
+        let err = model.generate_content(vec!["hi".into()]).await.unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::InvalidArgument));
+        assert!(err.message.contains("Harassment"));
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn model_from_name() {
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .model("gemini-1.5-flash")
+            .build();
+        assert!(matches!(model.model, GeminiModel::Flash_1_5));
+
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .model(String::from("gemini-2.0-flash"))
+            .build();
+        assert!(matches!(model.model, GeminiModel::Custom(ref name) if name == "gemini-2.0-flash"));
+        assert_eq!(model.model.to_string(), "gemini-2.0-flash");
+    }
+
+    #[test]
+    fn specialized_model_names() {
+        assert_eq!(GeminiModel::Aqa.to_string(), "aqa");
+        assert_eq!(GeminiModel::Gemma2_9B.to_string(), "gemma-2-9b-it");
+        assert_eq!(GeminiModel::from("aqa"), GeminiModel::Aqa);
+        assert_eq!(GeminiModel::from("gemma-2-27b-it"), GeminiModel::Gemma2_27B);
+    }
+
+    fn response(text: &str, finish_reason: &str) -> GeminiResponse {
+        serde_json::from_value(serde_json::json!({
+            "candidates": [{
+                "content": { "role": "model", "parts": [{ "text": text }] },
+                "finishReason": finish_reason
+            }],
+            "usageMetadata": {}
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn continuation_stops_at_stop() {
+        let mut prompts = Vec::new();
+        let mut responses = vec![
+            response("Once upon", "MAX_TOKENS"),
+            response(" a time.", "STOP"),
+        ]
+        .into_iter();
+
+        let text = continue_generation(vec!["tell a story".into()], 5, |contents| {
+            prompts.push(contents.len());
+            std::future::ready(Ok(responses.next().unwrap()))
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(text, "Once upon a time.");
+        // the second request carries the partial output and the continue instruction
+        assert_eq!(prompts, [1, 3]);
+    }
+
+    #[tokio::test]
+    async fn continuation_respects_round_cap() {
+        let mut calls = 0;
+        let text = continue_generation(vec!["tell a story".into()], 3, |_| {
+            calls += 1;
+            std::future::ready(Ok(response("more ", "MAX_TOKENS")))
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(calls, 3);
+        assert_eq!(text, "more more more ");
+
+        let err = continue_generation(vec!["tell a story".into()], 0, |_| {
+            calls += 1;
+            std::future::ready(Ok(response("more ", "MAX_TOKENS")))
+        })
+        .await
+        .unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::InvalidArgument));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn unparseable_body_is_included_in_error() {
+        let err = parse_response::<GeminiResponse>("<html>502 Bad Gateway</html>").unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::Other));
+        assert!(err.message.contains("<html>502 Bad Gateway</html>"));
+
+        let long_body = "x".repeat(2000);
+        let err = parse_response::<GeminiResponse>(&long_body).unwrap_err();
+        assert!(err.message.ends_with(&format!("{}...", "x".repeat(500))));
+    }
+
+    #[test]
+    fn api_errors_are_parsed() {
+        let err = parse_response::<GeminiResponse>(
+            r#"{"error": {"code": 429, "message": "slow down", "status": "RESOURCE_EXHAUSTED"}}"#,
+        )
+        .unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::ResourceExhausted));
+        assert_eq!(err.message, "slow down");
+    }
+
+    #[test]
+    fn custom_endpoint_keeps_standard_path() {
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .endpoint("gateway.example.com")
+            .build();
+        assert_eq!(
+            model.url(&model.model, "generateContent"),
+            "https://gateway.example.com/v1beta/models/gemini-1.5-pro:generateContent"
+        );
+
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .endpoint("http://localhost:8080/")
+            .model(GeminiModel::TextEmbedding004)
+            .build();
+        assert_eq!(
+            model.url(&model.model, "embedContent"),
+            "http://localhost:8080/v1beta/models/text-embedding-004:embedContent"
+        );
+
+        let model = GenerativeModelBuilder::new().api_key("key").build();
+        assert_eq!(
+            model.url(&model.model, "generateContent"),
+            format!("{BASE_URL}/models/gemini-1.5-pro:generateContent")
+        );
+    }
+
+    #[tokio::test]
+    async fn raw_json_round_trip() {
+        let reply = serde_json::json!({ "candidates": [], "somethingNew": { "x": 1 } });
+        let server = MockServer::ok(reply.clone()).await;
+        let model = server.model().model(GeminiModel::Flash_1_5).build();
+
+        let body = serde_json::json!({
+            "contents": [{ "parts": [{ "text": "hi" }] }],
+            "unmodeledField": true
+        });
+        let response = model.generate_content_raw_json(body.clone()).await.unwrap();
+
+        assert_eq!(response, reply);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert!(requests[0]
+            .path
+            .starts_with("/v1beta/models/gemini-1.5-flash:generateContent"));
+        assert_eq!(requests[0].header("content-type"), Some("application/json"));
+        assert_eq!(requests[0].json(), body);
+    }
+
+    #[tokio::test]
+    async fn raw_json_api_error() {
+        let server = MockServer::start(vec![(
+            400,
+            r#"{"error": {"code": 400, "message": "bad", "status": "INVALID_ARGUMENT"}}"#
+                .to_string(),
+        )])
+        .await;
+        let model = server.model().build();
+
+        let err = model
+            .generate_content_raw_json(serde_json::json!({}))
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::InvalidArgument));
+    }
+
+    #[tokio::test]
+    async fn sse_stream_keeps_an_unterminated_last_event() {
+        let chunk = |text: &str| {
+            serde_json::json!({
+                "candidates": [{ "content": { "role": "model", "parts": [{ "text": text }] } }],
+                "usageMetadata": {}
+            })
+        };
+        let body = format!("data: {}\n\ndata: {}", chunk("Hel"), chunk("lo"));
+        let server = MockServer::start(vec![(200, body)]).await;
+        let model = server.model().build();
+
+        let texts: Vec<String> = model
+            .generate_content_streamed(vec!["hi".into()])
+            .await
+            .unwrap()
+            .map(|chunk| chunk.unwrap().text().unwrap())
+            .collect()
+            .await;
+        assert_eq!(texts, ["Hel", "lo"]);
+        let path = &server.requests()[0].path;
+        assert!(path.contains(":streamGenerateContent?"));
+        assert!(path.contains("alt=sse"));
+    }
+
+    #[tokio::test]
+    async fn streamed_errors_are_returned() {
+        let body =
+            r#"{"error": {"code": 429, "message": "slow down", "status": "RESOURCE_EXHAUSTED"}}"#;
+        let server = MockServer::start(vec![(429, body.to_string())]).await;
+        let model = server.model().build();
+
+        let err = model
+            .generate_content_streamed(vec!["hi".into()])
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err.kind, GeminiErrorKind::ResourceExhausted));
+        assert_eq!(err.message, "slow down");
+    }
+
+    #[test]
+    fn models_as_map_keys() {
+        use std::{
+            collections::{hash_map::DefaultHasher, HashMap},
+            hash::{Hash, Hasher},
+        };
+
+        let hash = |model: &GeminiModel| {
+            let mut hasher = DefaultHasher::new();
+            model.hash(&mut hasher);
+            hasher.finish()
+        };
+        let owned = GeminiModel::Custom(String::from("tuned-model").into());
+        let borrowed = GeminiModel::Custom("tuned-model".into());
+        assert_eq!(owned, borrowed);
+        assert_eq!(hash(&owned), hash(&borrowed));
+
+        assert_eq!(GeminiModel::Flash_1_5, GeminiModel::Flash_1_5);
+        assert_ne!(GeminiModel::Flash_1_5, GeminiModel::Flash_1_5_8B);
+
+        let mut latencies = HashMap::new();
+        latencies.insert(owned, 120);
+        latencies.insert(GeminiModel::Pro_1_5, 300);
+        assert_eq!(latencies[&borrowed], 120);
+    }
+
+    #[test]
+    fn system_instruction_from_file() {
+        let path = std::env::temp_dir().join(format!(
+            "rusty-gemini-system-instruction-{}.md",
+            std::process::id()
+        ));
+        fs::write(&path, "You are a helpful assistant.\nAnswer briefly.").unwrap();
+
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .system_instruction_from_file(&path)
+            .unwrap()
+            .build();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            model.system_instruction.unwrap().text_parts(),
+            ["You are a helpful assistant.\nAnswer briefly."]
+        );
+        assert!(GenerativeModelBuilder::new()
+            .system_instruction_from_file(&path)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn embed_query_and_document_task_types() {
+        let server =
+            MockServer::ok(serde_json::json!({ "embedding": { "values": [0.5, -0.5] } })).await;
+        let model = server.model().model(GeminiModel::TextEmbedding004).build();
+
+        let embedding = model.embed_query("best pizza?").await.unwrap();
+        assert_eq!(embedding.values, [0.5, -0.5]);
+        model
+            .embed_document("Pizza is great.", "Pizza")
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        let query = requests[0].json();
+        assert_eq!(query["taskType"], "RETRIEVAL_QUERY");
+        assert!(query.get("title").is_none());
+        let document = requests[1].json();
+        assert_eq!(document["taskType"], "RETRIEVAL_DOCUMENT");
+        assert_eq!(document["title"], "Pizza");
+        assert_eq!(document["content"]["parts"][0]["text"], "Pizza is great.");
+    }
+
+    #[tokio::test]
+    async fn unsupported_output_dimensionality_is_rejected() {
+        let server = MockServer::ok(serde_json::json!({ "embedding": { "values": [0.5] } })).await;
+        let model = server.model().model(GeminiModel::TextEmbedding004).build();
+        let config = |dimensionality| EmbedContentConfig {
+            output_dimensionality: Some(dimensionality),
+            ..Default::default()
+        };
+
+        let error = model.embed_content("hi", config(1024)).await.unwrap_err();
+        assert!(matches!(error.kind, GeminiErrorKind::InvalidArgument));
+        let error = model
+            .batch_embed_content(vec![("hi".into(), config(0))])
+            .await
+            .unwrap_err();
+        assert!(matches!(error.kind, GeminiErrorKind::InvalidArgument));
+        assert!(server.requests().is_empty());
+
+        model.embed_content("hi", config(256)).await.unwrap();
+        assert_eq!(server.requests()[0].json()["outputDimensionality"], 256);
+    }
+
+    #[tokio::test]
+    async fn api_key_is_sent_in_header_by_default() {
+        let server = MockServer::ok(serde_json::json!({ "embedding": { "values": [0.5] } })).await;
+        let model = server.model().model(GeminiModel::TextEmbedding004).build();
+        model.embed_query("hi").await.unwrap();
+        let _ = model
+            .generate_content_streamed(vec![Content::user("hi")])
+            .await
+            .unwrap();
+
+        for request in server.requests() {
+            assert_eq!(request.header("x-goog-api-key"), Some("test-key"));
+            assert!(!request.path.contains("key="));
+        }
+
+        let model = server
+            .model()
+            .model(GeminiModel::TextEmbedding004)
+            .auth_via_header(false)
+            .build();
+        model.embed_query("hi").await.unwrap();
+        let request = server.requests().pop().unwrap();
+        assert_eq!(request.header("x-goog-api-key"), None);
+        assert!(request.path.ends_with(":embedContent?key=test-key"));
+    }
+
+    #[tokio::test]
+    async fn stream_to_writer_writes_all_text() {
+        let body = [
+            (r#"{"text": "Hello"}"#, r#"{"promptTokenCount": 3}"#),
+            (r#"{"text": ", world"}"#, r#"{"promptTokenCount": 3, "candidatesTokenCount": 4}"#),
+        ]
+        .map(|(part, usage)| {
+            format!(
+                "data: {{\"candidates\": [{{\"content\": {{\"role\": \"model\", \"parts\": [{part}]}}}}], \"usageMetadata\": {usage}}}\n\n"
+            )
+        })
+        .concat();
+        let server = MockServer::start(vec![(200, body)]).await;
+        let model = server.model().build();
+
+        let mut output = Vec::new();
+        let usage = model
+            .stream_to_writer(vec![Content::user("hi")], &mut output)
+            .await
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "Hello, world");
+        assert_eq!(usage.candidate_tokens(), 4);
+    }
+
+    #[tokio::test]
+    async fn embedding_with_generation_model_is_rejected() {
+        let server = MockServer::ok(serde_json::json!({ "embedding": { "values": [0.5] } })).await;
+        let model = server.model().model(GeminiModel::Flash_1_5).build();
+
+        let error = model.embed_query("hi").await.unwrap_err();
+        assert!(matches!(error.kind, GeminiErrorKind::InvalidArgument));
+        assert!(error
+            .message
+            .contains("gemini-1.5-flash is not an embedding model"));
+        let error = model
+            .batch_embed_content(vec![("hi".into(), EmbedContentConfig::default())])
+            .await
+            .unwrap_err();
+        assert!(matches!(error.kind, GeminiErrorKind::InvalidArgument));
+        assert!(server.requests().is_empty());
+
+        let custom = server.model().model("gemini-embedding-exp").build();
+        custom.embed_query("hi").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn complete_returns_text_usage_and_finish_reason() {
+        let server = MockServer::ok(serde_json::json!({
+            "candidates": [{
+                "content": { "role": "model", "parts": [{ "text": "Hi!" }] },
+                "finishReason": "STOP"
+            }],
+            "usageMetadata": { "promptTokenCount": 2, "candidatesTokenCount": 1, "totalTokenCount": 3 }
+        }))
+        .await;
+        let model = server.model().build();
+
+        let completion = model.complete(vec![Content::user("Hello")]).await.unwrap();
+        assert_eq!(completion.text, "Hi!");
+        assert_eq!(completion.usage.total_tokens(), 3);
+        assert!(matches!(completion.finish_reason, Some(FinishReason::Stop)));
+    }
+
+    #[tokio::test]
+    async fn validate_key() {
+        let server = MockServer::ok(serde_json::json!({ "name": "models/gemini-1.5-pro" })).await;
+        server.model().build().validate_key().await.unwrap();
+        let request = &server.requests()[0];
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/v1beta/models/gemini-1.5-pro");
+
+        let server = MockServer::start(vec![(
+            403,
+            r#"{"error": {"code": 403, "message": "denied", "status": "PERMISSION_DENIED"}}"#
+                .to_string(),
+        )])
+        .await;
+        let err = server.model().build().validate_key().await.unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::Unauthenticated));
+        assert_eq!(err.message, "denied");
+    }
+
+    #[tokio::test]
+    async fn rank_by_similarity() {
+        let server = MockServer::ok(serde_json::json!({
+            "embeddings": [
+                { "values": [1.0, 0.0] },
+                { "values": [0.0, 1.0] },
+                { "values": [1.0, 0.1] },
+                { "values": [1.0, 1.0] }
+            ]
+        }))
+        .await;
+        let model = server.model().model(GeminiModel::TextEmbedding004).build();
+
+        let documents = ["cats", "the best pizza", "pizza and cats"];
+        let ranking = model
+            .rank_by_similarity("pizza", documents.map(String::from).to_vec())
+            .await
+            .unwrap();
+        let order: Vec<_> = ranking.iter().map(|(index, _)| *index).collect();
+        assert_eq!(order, [1, 2, 0]);
+        assert!(ranking[0].1 > 0.99);
+
+        let request = server.requests()[0].json();
+        assert_eq!(request["requests"][0]["taskType"], "RETRIEVAL_QUERY");
+        assert_eq!(request["requests"][1]["taskType"], "RETRIEVAL_DOCUMENT");
+        assert_eq!(
+            request["requests"][3]["content"]["parts"][0]["text"],
+            "pizza and cats"
+        );
+    }
+
+    #[test]
+    fn dry_run_request_json() {
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .tool(function_tool("lookup"))
+            .build();
+        let prompt = || vec![Content::user("hi")];
+
+        let compact = model
+            .build_request_json(prompt(), GenerativeModelBuilder::new())
+            .unwrap();
+        let pretty = model
+            .build_request_pretty(prompt(), GenerativeModelBuilder::new())
+            .unwrap();
+        assert!(!compact.contains('\n'));
+        assert!(pretty.contains('\n'));
+        let compact: serde_json::Value = serde_json::from_str(&compact).unwrap();
+        let pretty: serde_json::Value = serde_json::from_str(&pretty).unwrap();
+        assert_eq!(compact, pretty);
+        assert_eq!(
+            compact["tools"][0]["functionDeclarations"][0]["name"],
+            "lookup"
+        );
+
+        let mut invalid = GenerativeModelBuilder::new();
+        invalid.candidate_count(9);
+        assert!(model.build_request_json(prompt(), invalid).is_err());
+    }
+
+    // Models are shared across tasks, so they and the futures they return must stay
+    // `Send + Sync`; these fail to compile if a field or future loses those bounds.
+    const _: fn() = || {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<GenerativeModel>();
+        assert_send_sync::<GenerativeModelBuilder>();
+        assert_send_sync::<ChatSession>();
+        assert_send_sync::<GeminiResponse>();
+        assert_send_sync::<GeminiError>();
+    };
+
+    #[test]
+    fn futures_are_send() {
+        fn assert_send<T: Send>(_: T) {}
+        let model = GenerativeModelBuilder::new().api_key("key").build();
+        assert_send(model.generate_content(vec![]));
+        assert_send(model.generate_content_streamed(vec![]));
+        assert_send(model.embed_query("hi"));
+        assert_send(model.run_agent(vec![], |_, _| serde_json::Value::Null, 1));
+        let mut chat = model.start_chat(vec![]);
+        assert_send(chat.send_message(Content::user("hi")));
+    }
+
+    #[tokio::test]
+    async fn tweaked_config_keeps_other_settings() {
+        let server = MockServer::ok(serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": "ok" }] } }],
+            "usageMetadata": {}
+        }))
+        .await;
+        let model = server
+            .model()
+            .generation_config(GenerationConfig::balanced())
+            .build();
+
+        model
+            .generate_content_tweaked(vec![Content::user("hi")], |config| {
+                config.temperature = Some(0.2)
+            })
+            .await
+            .unwrap();
+
+        let config = &server.requests()[0].json()["generation_config"];
+        assert_eq!(config["temperature"].as_f64().unwrap() as f32, 0.2);
+        assert_eq!(config["topK"], 40);
+        assert_eq!(config["topP"].as_f64().unwrap() as f32, 0.95);
+        assert_eq!(
+            model.generation_config.unwrap().temperature,
+            Some(0.7),
+            "the model's own configuration is unchanged"
+        );
+    }
+
+    #[tokio::test]
+    async fn every_request_uses_the_configured_client() {
+        let server = MockServer::ok(serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": "ok" }] } }],
+            "usageMetadata": {}
+        }))
+        .await;
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-client", "shared".parse().unwrap());
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .unwrap();
+        let model = server.model().client(client).build();
+
+        model.generate_content(vec!["hi".into()]).await.unwrap();
+        let _ = model
+            .clone()
+            .generate_content_streamed(vec!["hi".into()])
+            .await
+            .unwrap();
+        let mut chat = model.start_chat(vec![]);
+        chat.send_message(Content::user("hi")).await.unwrap();
+        let _ = model
+            .clone_with_model(GeminiModel::Flash_1_5)
+            .count_tokens(vec!["hi".into()])
+            .await;
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        for request in requests {
+            assert_eq!(request.header("x-client"), Some("shared"));
+        }
+    }
+
+    #[test]
+    fn labels_are_serialized() {
+        let model = GenerativeModelBuilder::new()
+            .api_key("key")
+            .label("team", "search")
+            .label("feature", "autocomplete")
+            .build();
+        let request = model.build_request(vec!["hi".into()], GenerativeModelBuilder::new());
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(
+            json["labels"],
+            serde_json::json!({ "team": "search", "feature": "autocomplete" })
+        );
+
+        let mut model = GenerativeModelBuilder::new().api_key("key").build();
+        let request = model.build_request(vec!["hi".into()], GenerativeModelBuilder::new());
+        assert!(serde_json::to_value(&request)
+            .unwrap()
+            .get("labels")
+            .is_none());
+
+        model.labels = Some(HashMap::new());
+        let request = model.build_request(vec!["hi".into()], GenerativeModelBuilder::new());
+        assert!(serde_json::to_value(&request)
+            .unwrap()
+            .get("labels")
+            .is_none());
+    }
+
+    #[cfg(feature = "tracing")]
+    type CapturedSpan = (&'static str, HashMap<String, String>);
+
+    /// Records the name and field values of every span.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct SpanCapture {
+        spans: std::sync::Arc<std::sync::Mutex<Vec<CapturedSpan>>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl<S> tracing_subscriber::Layer<S> for SpanCapture
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::Id,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = HashMap::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((attrs.metadata().name(), fields));
+            // Remember where the span's fields are, for later records.
+            ctx.span(id)
+                .unwrap()
+                .extensions_mut()
+                .insert(spans.len() - 1);
+        }
+
+        fn on_record(
+            &self,
+            id: &tracing::Id,
+            values: &tracing::span::Record<'_>,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let index = *ctx.span(id).unwrap().extensions().get::<usize>().unwrap();
+            values.record(&mut FieldVisitor(&mut self.spans.lock().unwrap()[index].1));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn requests_emit_spans() {
+        let server = MockServer::ok(serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": "hi" }] } }],
+            "usageMetadata": { "promptTokenCount": 3, "candidatesTokenCount": 1, "totalTokenCount": 4 }
+        }))
+        .await;
+        let model = server.model().build();
+        let capture = SpanCapture::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::layer::SubscriberExt::with(
+                tracing_subscriber::registry(),
+                capture.clone(),
+            ));
+
+        model.generate_content(vec!["hi".into()]).await.unwrap();
+
+        let spans = capture.spans.lock().unwrap();
+        let (name, fields) = &spans[0];
+        assert_eq!(*name, "gemini.generate_content");
+        assert_eq!(fields["prompt_token_count"], "3");
+        assert_eq!(fields["total_token_count"], "4");
+        let (name, fields) = &spans[1];
+        assert_eq!(*name, "gemini.send_request");
+        assert_eq!(fields["model"], "gemini-1.5-pro");
+        assert_eq!(fields["status"], "200");
+        assert!(fields.contains_key("latency_ms"));
+    }
+
+    #[tokio::test]
+    async fn streamed_calls_run_callbacks_with_context() {
+        let chunk = |text: &str| {
+            serde_json::json!({
+                "candidates": [{ "content": { "role": "model", "parts": [{ "text": text }] } }],
+                "usageMetadata": {}
+            })
+        };
+        let body = format!("data: {}\n\ndata: {}\n\n", chunk("Hel"), chunk("lo"));
+        let error = serde_json::json!({
+            "error": { "code": 400, "message": "bad", "status": "INVALID_ARGUMENT" }
+        });
+        let server = MockServer::start(vec![(200, body), (400, error.to_string())]).await;
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let responses = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (requests_seen, responses_seen) = (requests.clone(), responses.clone());
+        let context = serde_json::json!({ "user": "u-1" });
+        let model = server
+            .model()
+            .request_context(context.clone())
+            .on_request(move |_, _| {
+                requests_seen.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            })
+            .on_response(move |result, context| {
+                responses_seen
+                    .lock()
+                    .unwrap()
+                    .push((result.is_ok(), context.cloned()));
+            })
+            .build();
+
+        let chunks: Vec<_> = model
+            .generate_content_streamed(vec!["hi".into()])
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(
+            *responses.lock().unwrap(),
+            [(true, Some(context.clone())), (true, Some(context.clone()))]
+        );
+
+        let err = model
+            .generate_content_streamed(vec!["hi".into()])
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.context, Some(context.clone()));
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(responses.lock().unwrap()[2], (false, Some(context)));
+    }
+
+    #[tokio::test]
+    async fn request_context_reaches_callbacks_and_errors() {
+        let server = MockServer::start(vec![
+            (
+                200,
+                serde_json::json!({
+                    "candidates": [{ "content": { "role": "model", "parts": [{ "text": "ok" }] } }],
+                    "usageMetadata": {}
+                })
+                .to_string(),
+            ),
+            (
+                400,
+                serde_json::json!({
+                    "error": { "code": 400, "message": "bad", "status": "INVALID_ARGUMENT" }
+                })
+                .to_string(),
+            ),
+        ])
+        .await;
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (on_request_seen, on_response_seen) = (seen.clone(), seen.clone());
+        let context = serde_json::json!({ "user": "u-1", "trace": [1, 2] });
+        let model = server
+            .model()
+            .request_context(context.clone())
+            .on_request(move |_, context| {
+                on_request_seen.lock().unwrap().push(context.cloned());
+            })
+            .on_response(move |result, context| {
+                assert!(result.is_ok());
+                on_response_seen.lock().unwrap().push(context.cloned());
+            })
+            .build();
+
+        model.generate_content(vec!["hi".into()]).await.unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [Some(context.clone()), Some(context)]
+        );
+        assert!(server.requests()[0].json().get("request_context").is_none());
+
+        let mut config = GenerativeModelBuilder::new();
+        config.request_context(serde_json::json!("per call"));
+        let model = GenerativeModel {
+            callbacks: Callbacks::default(),
+            ..model
+        };
+        let err = model
+            .generate_content_with(vec!["hi".into()], config)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::InvalidArgument));
+        assert_eq!(err.context, Some(serde_json::json!("per call")));
+    }
+
+    #[tokio::test]
+    async fn channel_yields_every_chunk() {
+        let body: String = ["Hel", "lo, ", "world"]
+            .iter()
+            .map(|text| {
+                let chunk = serde_json::json!({
+                    "candidates": [{ "content": { "role": "model", "parts": [{ "text": text }] } }],
+                    "usageMetadata": {}
+                });
+                format!("data: {chunk}\n\n")
+            })
+            .collect();
+        let server = MockServer::start(vec![(200, body)]).await;
+        let model = server.model().build();
+
+        let (handle, mut receiver) = model.generate_content_channel(vec!["hi".into()]);
+        let mut texts = Vec::new();
+        while let Some(chunk) = receiver.recv().await {
+            texts.push(chunk.unwrap().text().unwrap());
+        }
+        assert_eq!(texts, ["Hel", "lo, ", "world"]);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tools_on_embedding_model_are_rejected_when_strict() {
+        let server = MockServer::ok(serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": "ok" }] } }],
+            "usageMetadata": {}
+        }))
+        .await;
+        let model = server
+            .model()
+            .model(GeminiModel::TextEmbedding004)
+            .tool(function_tool("lookup"))
+            .strict_tool_check(true)
+            .build();
+
+        let err = model.generate_content(vec!["hi".into()]).await.unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::InvalidArgument));
+        assert!(err.message.contains("text-embedding-004"));
+        assert!(server.requests().is_empty());
+
+        let lenient = GenerativeModel {
+            strict_tool_check: false,
+            ..model.clone()
+        };
+        lenient.generate_content(vec!["hi".into()]).await.unwrap();
+        let tool_model = model.clone_with_model(GeminiModel::Flash_1_5);
+        tool_model
+            .generate_content(vec!["hi".into()])
+            .await
+            .unwrap();
+        assert_eq!(server.requests().len(), 2);
+
+        let gemma = model.clone_with_model(GeminiModel::Gemma2_9B);
+        let err = gemma.generate_content(vec!["hi".into()]).await.unwrap_err();
+        assert!(matches!(err.kind, GeminiErrorKind::InvalidArgument));
+        assert_eq!(server.requests().len(), 2);
     }
 }
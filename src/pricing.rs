@@ -0,0 +1,84 @@
+//! Per-model prices, used to estimate what a request will cost before sending it.
+
+use std::collections::HashMap;
+
+use crate::model::GeminiModel;
+
+/// The price of a model's tokens, in US dollars per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pricing {
+    pub input_per_million: f64,
+    pub output_per_million: f64,
+}
+
+/// The [Pricing] of each model.
+///
+/// The default table holds the pay-as-you-go list prices of the Gemini 1.5 models for
+/// prompts up to 128k tokens. Prices change, so override them with [PriceTable::set]
+/// (and add any other model you use) rather than relying on the defaults.
+#[derive(Debug, Clone)]
+pub struct PriceTable {
+    prices: HashMap<GeminiModel, Pricing>,
+}
+
+impl Default for PriceTable {
+    fn default() -> Self {
+        let mut table = Self::empty();
+        table
+            .set(GeminiModel::Pro_1_5, Pricing::new(1.25, 5.0))
+            .set(GeminiModel::Flash_1_5, Pricing::new(0.075, 0.3))
+            .set(GeminiModel::Flash_1_5_8B, Pricing::new(0.0375, 0.15));
+        table
+    }
+}
+
+impl PriceTable {
+    /// A table without any prices.
+    pub fn empty() -> Self {
+        Self {
+            prices: HashMap::new(),
+        }
+    }
+
+    /// Sets the pricing of `model`, replacing any pricing set for it before.
+    pub fn set(&mut self, model: GeminiModel, pricing: Pricing) -> &mut Self {
+        self.prices.insert(model, pricing);
+        self
+    }
+
+    pub fn get(&self, model: &GeminiModel) -> Option<&Pricing> {
+        self.prices.get(model)
+    }
+}
+
+impl Pricing {
+    pub fn new(input_per_million: f64, output_per_million: f64) -> Self {
+        Self {
+            input_per_million,
+            output_per_million,
+        }
+    }
+
+    /// Estimates the cost of sending `input_tokens` and receiving `output_tokens`.
+    pub fn estimate(&self, input_tokens: i32, output_tokens: i32) -> CostEstimate {
+        let input_cost = f64::from(input_tokens) * self.input_per_million / 1_000_000.0;
+        let output_cost = f64::from(output_tokens) * self.output_per_million / 1_000_000.0;
+        CostEstimate {
+            input_tokens,
+            output_tokens,
+            input_cost,
+            output_cost,
+            total_cost: input_cost + output_cost,
+        }
+    }
+}
+
+/// The estimated cost of a request, in US dollars.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CostEstimate {
+    pub input_tokens: i32,
+    pub output_tokens: i32,
+    pub input_cost: f64,
+    pub output_cost: f64,
+    pub total_cost: f64,
+}
@@ -0,0 +1,61 @@
+//! Configuration for retrying requests that failed with a transient error.
+
+use std::time::Duration;
+
+/// How requests that fail with a [retryable](crate::error::GeminiError::is_retryable)
+/// error are retried, with exponential backoff between attempts.
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    /// How many times a request is retried before its error is returned.
+    pub max_retries: usize,
+    /// The delay before the first retry; each later retry waits twice as long.
+    pub initial_backoff: Duration,
+    /// The longest delay between two attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(8),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// A configuration that never retries.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// The delay before retry number `retry`, counting from 0.
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let factor = 2u32.saturating_pow(retry.try_into().unwrap_or(u32::MAX));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let config = RetryConfig {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        };
+        assert_eq!(config.backoff(0), Duration::from_millis(100));
+        assert_eq!(config.backoff(2), Duration::from_millis(400));
+        assert_eq!(config.backoff(3), Duration::from_millis(500));
+        assert_eq!(config.backoff(100), Duration::from_millis(500));
+    }
+}
@@ -2,13 +2,13 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Schema {
     #[serde(rename = "type")]
     pub schema_type: Type,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub format: Option<String>,
+    pub format: Option<SchemaFormat>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
     #[serde(default)]
@@ -24,11 +24,15 @@ pub struct Schema {
     pub properties: Option<HashMap<String, Box<Schema>>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub required: Option<Vec<String>>,
+    /// The order in which the model should write `properties`, which can improve the
+    /// quality of structured output.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub property_ordering: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub items: Option<Box<Schema>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Type {
     #[serde(rename = "TYPE_UNSPECIFIED")]
@@ -39,4 +43,284 @@ pub enum Type {
     Boolean,
     Array,
     Object,
-}
\ No newline at end of file
+}
+
+/// The format of a [Schema]'s values, refining its type.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(from = "String", into = "String")]
+pub enum SchemaFormat {
+    /// A 32-bit integer (`int32`).
+    Int32,
+    /// A 64-bit integer (`int64`).
+    Int64,
+    /// A single-precision number (`float`).
+    Float,
+    /// A double-precision number (`double`).
+    Double,
+    /// A full date, e.g. `2024-05-17` (`date`).
+    Date,
+    /// A date and time, e.g. `2024-05-17T12:00:00Z` (`date-time`).
+    DateTime,
+    /// A string restricted to `enum_values` (`enum`).
+    Enum,
+    /// Any other format, sent as is.
+    Custom(String),
+}
+
+impl From<String> for SchemaFormat {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "int32" => SchemaFormat::Int32,
+            "int64" => SchemaFormat::Int64,
+            "float" => SchemaFormat::Float,
+            "double" => SchemaFormat::Double,
+            "date" => SchemaFormat::Date,
+            "date-time" => SchemaFormat::DateTime,
+            "enum" => SchemaFormat::Enum,
+            _ => SchemaFormat::Custom(value),
+        }
+    }
+}
+
+impl From<SchemaFormat> for String {
+    fn from(value: SchemaFormat) -> Self {
+        match value {
+            SchemaFormat::Int32 => "int32".to_string(),
+            SchemaFormat::Int64 => "int64".to_string(),
+            SchemaFormat::Float => "float".to_string(),
+            SchemaFormat::Double => "double".to_string(),
+            SchemaFormat::Date => "date".to_string(),
+            SchemaFormat::DateTime => "date-time".to_string(),
+            SchemaFormat::Enum => "enum".to_string(),
+            SchemaFormat::Custom(format) => format,
+        }
+    }
+}
+
+impl Schema {
+    /// A schema of the given type with every other field unset.
+    pub fn new(schema_type: Type) -> Self {
+        Self {
+            schema_type,
+            format: None,
+            description: None,
+            nullable: false,
+            enum_values: None,
+            max_items: None,
+            min_items: None,
+            properties: None,
+            required: None,
+            property_ordering: None,
+            items: None,
+        }
+    }
+
+    /// Sets the order in which the model should write the properties of this object schema.
+    pub fn property_ordering<'a>(mut self, names: impl IntoIterator<Item = &'a str>) -> Self {
+        self.property_ordering = Some(names.into_iter().map(str::to_string).collect());
+        self
+    }
+
+    /// An object schema with the given properties.
+    ///
+    /// Every property that isn't nullable is marked as required.
+    pub fn object<'a>(properties: impl IntoIterator<Item = (&'a str, Schema)>) -> Self {
+        let mut required = Vec::new();
+        let properties = properties
+            .into_iter()
+            .map(|(name, schema)| {
+                if !schema.nullable {
+                    required.push(name.to_string());
+                }
+                (name.to_string(), Box::new(schema))
+            })
+            .collect();
+
+        Self {
+            properties: Some(properties),
+            required: Some(required),
+            ..Self::new(Type::Object)
+        }
+    }
+
+    fn with_format(schema_type: Type, format: SchemaFormat) -> Self {
+        Self {
+            format: Some(format),
+            ..Self::new(schema_type)
+        }
+    }
+}
+
+/// Types that can describe themselves as a [Schema], e.g. to use as a `response_schema`.
+///
+/// Implemented for primitives, `Vec<T>` and `Option<T>`; implement it for your own
+/// structs with [Schema::object] over the schemas of their fields.
+pub trait SchemaFrom {
+    fn schema() -> Schema;
+}
+
+impl SchemaFrom for String {
+    fn schema() -> Schema {
+        Schema::new(Type::String)
+    }
+}
+
+impl SchemaFrom for &str {
+    fn schema() -> Schema {
+        Schema::new(Type::String)
+    }
+}
+
+impl SchemaFrom for bool {
+    fn schema() -> Schema {
+        Schema::new(Type::Boolean)
+    }
+}
+
+macro_rules! integer_schema {
+    ($format:expr; $($ty:ty),*) => {
+        $(
+            impl SchemaFrom for $ty {
+                fn schema() -> Schema {
+                    Schema::with_format(Type::Integer, $format)
+                }
+            }
+        )*
+    };
+}
+
+integer_schema!(SchemaFormat::Int32; i8, i16, i32, u8, u16);
+integer_schema!(SchemaFormat::Int64; i64, u32, u64, isize, usize);
+
+impl SchemaFrom for f32 {
+    fn schema() -> Schema {
+        Schema::with_format(Type::Number, SchemaFormat::Float)
+    }
+}
+
+impl SchemaFrom for f64 {
+    fn schema() -> Schema {
+        Schema::with_format(Type::Number, SchemaFormat::Double)
+    }
+}
+
+impl<T: SchemaFrom> SchemaFrom for Vec<T> {
+    fn schema() -> Schema {
+        Schema {
+            items: Some(Box::new(T::schema())),
+            ..Schema::new(Type::Array)
+        }
+    }
+}
+
+impl<T: SchemaFrom> SchemaFrom for Option<T> {
+    fn schema() -> Schema {
+        Schema {
+            nullable: true,
+            ..T::schema()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(dead_code)]
+    struct Recipe {
+        name: String,
+        servings: Option<u8>,
+        ingredients: Vec<String>,
+    }
+
+    impl SchemaFrom for Recipe {
+        fn schema() -> Schema {
+            Schema::object([
+                ("name", String::schema()),
+                ("servings", Option::<u8>::schema()),
+                ("ingredients", Vec::<String>::schema()),
+            ])
+        }
+    }
+
+    #[test]
+    fn struct_schema_matches_hand_written() {
+        let string = Schema {
+            schema_type: Type::String,
+            format: None,
+            description: None,
+            nullable: false,
+            enum_values: None,
+            max_items: None,
+            min_items: None,
+            properties: None,
+            required: None,
+            property_ordering: None,
+            items: None,
+        };
+        let servings = Schema {
+            schema_type: Type::Integer,
+            format: Some(SchemaFormat::Int32),
+            nullable: true,
+            ..string.clone()
+        };
+        let ingredients = Schema {
+            schema_type: Type::Array,
+            items: Some(Box::new(string.clone())),
+            ..string.clone()
+        };
+        let expected = Schema {
+            schema_type: Type::Object,
+            properties: Some(HashMap::from([
+                ("name".to_string(), Box::new(string.clone())),
+                ("servings".to_string(), Box::new(servings)),
+                ("ingredients".to_string(), Box::new(ingredients)),
+            ])),
+            required: Some(vec!["name".to_string(), "ingredients".to_string()]),
+            ..string.clone()
+        };
+
+        assert_eq!(Recipe::schema(), expected);
+    }
+
+    #[test]
+    fn schema_formats() {
+        let formats = [
+            (SchemaFormat::Int32, "int32"),
+            (SchemaFormat::Int64, "int64"),
+            (SchemaFormat::Float, "float"),
+            (SchemaFormat::Double, "double"),
+            (SchemaFormat::Date, "date"),
+            (SchemaFormat::DateTime, "date-time"),
+            (SchemaFormat::Enum, "enum"),
+            (SchemaFormat::Custom("uuid".to_string()), "uuid"),
+        ];
+        for (format, expected) in formats {
+            let json = serde_json::to_value(&format).unwrap();
+            assert_eq!(json, expected);
+            assert_eq!(
+                serde_json::from_value::<SchemaFormat>(json).unwrap(),
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn property_ordering_of_nested_object() {
+        let address = Schema::object([("street", String::schema()), ("city", String::schema())])
+            .property_ordering(["street", "city"]);
+        let person = Schema::object([("name", String::schema()), ("address", address)])
+            .property_ordering(["name", "address"]);
+
+        let json = serde_json::to_value(&person).unwrap();
+        assert_eq!(
+            json["propertyOrdering"],
+            serde_json::json!(["name", "address"])
+        );
+        assert_eq!(
+            json["properties"]["address"]["propertyOrdering"],
+            serde_json::json!(["street", "city"])
+        );
+        assert!(json["properties"]["name"].get("propertyOrdering").is_none());
+    }
+}
@@ -0,0 +1,924 @@
+use std::{
+    future::{ready, Future},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::{stream, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{
+    api::{Candidate, UsageMetadata},
+    content::Part,
+    error::GeminiError,
+    GeminiResponse,
+};
+
+/// Extension methods for streams returned by `generate_content_streamed`.
+pub trait GeminiStreamExt: Stream<Item = Result<GeminiResponse, GeminiError>> + Sized {
+    /// Yields only the text that each chunk adds to the response so far.
+    ///
+    /// The API streams incrementally, so this is the text of each chunk's first
+    /// candidate. Chunks adding no text are skipped.
+    fn text_deltas(self) -> impl Stream<Item = Result<String, GeminiError>> {
+        self.map(|chunk| chunk.map(|response| chunk_text(&response)))
+            .filter(|delta| ready(!matches!(delta, Ok(delta) if delta.is_empty())))
+    }
+
+    /// Like [GeminiStreamExt::text_deltas], for streams where each chunk holds the whole
+    /// text generated so far (e.g. from a proxy that accumulates responses).
+    ///
+    /// Each chunk is compared with the previous one; a chunk that doesn't extend it is
+    /// yielded whole.
+    fn cumulative_text_deltas(self) -> impl Stream<Item = Result<String, GeminiError>> {
+        let mut previous = String::new();
+        self.map(move |chunk| {
+            chunk.map(|response| text_delta(&mut previous, chunk_text(&response)))
+        })
+        .filter(|delta| ready(!matches!(delta, Ok(delta) if delta.is_empty())))
+    }
+
+    /// Yields the parts of the first candidate of each chunk as they arrive.
+    ///
+    /// This makes it easy to route text to the screen and function calls to a dispatcher.
+    fn parts_stream(self) -> impl Stream<Item = Result<Part, GeminiError>> {
+        self.flat_map(|chunk| {
+            let parts = match chunk {
+                Ok(response) => response
+                    .candidates
+                    .into_iter()
+                    .next()
+                    .map(|candidate| candidate.content.parts.into_iter().map(Ok).collect())
+                    .unwrap_or_default(),
+                Err(err) => vec![Err(err)],
+            };
+            stream::iter(parts)
+        })
+    }
+
+    /// Calls `callback` with the name and arguments of every function call in the first
+    /// candidate as soon as its chunk arrives, passing the chunks through unchanged.
+    ///
+    /// This lets an agent start running a tool before the rest of the response has streamed.
+    fn on_function_call<F>(self, mut callback: F) -> impl Stream<Item = Self::Item>
+    where
+        F: FnMut(&str, Option<&Value>),
+    {
+        self.inspect(move |chunk| {
+            if let Some(candidate) = chunk
+                .as_ref()
+                .ok()
+                .and_then(|response| response.candidates.first())
+            {
+                for (name, args) in candidate.function_calls() {
+                    callback(name, args);
+                }
+            }
+        })
+    }
+
+    /// Buffers the text of a structured-output stream (e.g. with an `application/json`
+    /// response MIME type) and yields it as a `T` once the buffered JSON is complete.
+    ///
+    /// Chunks usually hold fragments of the JSON that can't be parsed on their own.
+    /// Objects and arrays are yielded as soon as they close; a top-level scalar (e.g.
+    /// `12` followed by `3`) could still continue, so it is only parsed once the stream
+    /// ends. Invalid JSON, or JSON left incomplete when the stream ends, yields an error.
+    fn json_stream<T: DeserializeOwned>(self) -> impl Stream<Item = Result<T, GeminiError>> {
+        let chunks = Box::pin(self);
+        stream::unfold(
+            (chunks, String::new(), false),
+            |(mut chunks, mut buffer, done)| async move {
+                if done {
+                    return None;
+                }
+                loop {
+                    let next = chunks.next().await;
+                    let ended = next.is_none();
+                    let item = match next {
+                        Some(Ok(response)) => {
+                            buffer.push_str(&chunk_text(&response));
+                            if !buffer.trim_start().starts_with(['{', '[']) {
+                                continue;
+                            }
+                            match serde_json::from_str::<T>(&buffer) {
+                                Ok(value) => {
+                                    buffer.clear();
+                                    Ok(value)
+                                }
+                                Err(err) if err.is_eof() => continue,
+                                Err(err) => Err(GeminiError::message(&format!(
+                                    "the streamed JSON is invalid: {err}"
+                                ))),
+                            }
+                        }
+                        Some(Err(err)) => Err(err),
+                        None if buffer.trim().is_empty() => return None,
+                        None => match serde_json::from_str::<T>(&buffer) {
+                            Ok(value) => Ok(value),
+                            Err(err) if err.is_eof() => Err(GeminiError::message(
+                                "the stream ended before the JSON was complete",
+                            )),
+                            Err(err) => Err(GeminiError::message(&format!(
+                                "the streamed JSON is invalid: {err}"
+                            ))),
+                        },
+                    };
+                    let done = ended || item.is_err();
+                    return Some((item, (chunks, buffer, done)));
+                }
+            },
+        )
+    }
+
+    /// Wraps the stream to remember the usage reported by its chunks, available from
+    /// [UsageTrackingStream::final_usage] once the stream ends.
+    ///
+    /// The API always sends the complete usage with the last chunk, so no request
+    /// option is needed for it.
+    fn track_usage(self) -> UsageTrackingStream<Self> {
+        UsageTrackingStream {
+            inner: Box::pin(self),
+            usage: None,
+        }
+    }
+}
+
+impl<S> GeminiStreamExt for S where S: Stream<Item = Result<GeminiResponse, GeminiError>> {}
+
+/// A stream of response chunks that remembers the latest usage reported, as returned
+/// by [GeminiStreamExt::track_usage].
+pub struct UsageTrackingStream<S> {
+    inner: Pin<Box<S>>,
+    usage: Option<UsageMetadata>,
+}
+
+impl<S> UsageTrackingStream<S> {
+    /// The usage reported by the latest chunk that had any, which is the usage of the
+    /// whole response once the stream has ended.
+    pub fn final_usage(&self) -> Option<&UsageMetadata> {
+        self.usage.as_ref()
+    }
+}
+
+impl<S> Stream for UsageTrackingStream<S>
+where
+    S: Stream<Item = Result<GeminiResponse, GeminiError>>,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.inner.as_mut().poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            let usage = &chunk.usage_metadata;
+            if usage.total_token_count.is_some() || usage.prompt_token_count.is_some() {
+                self.usage = Some(usage.clone());
+            }
+        }
+        poll
+    }
+}
+
+/// Merges the chunks of a streamed response back into a single response.
+///
+/// Chunks are matched to candidates by position. Adjacent text parts are joined,
+/// citations and grounding attributions are concatenated, and the latest finish
+/// reason and safety ratings win. Token counts are taken from the last chunk that has
+/// them, since each chunk reports the running totals so far.
+#[derive(Debug, Default)]
+pub struct ResponseAccumulator {
+    response: Option<GeminiResponse>,
+}
+
+impl ResponseAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Merges the next chunk of the stream into the response so far.
+    pub fn push(&mut self, chunk: &GeminiResponse) {
+        let Some(response) = &mut self.response else {
+            self.response = Some(chunk.clone());
+            return;
+        };
+
+        for (index, candidate) in chunk.candidates.iter().enumerate() {
+            match response.candidates.get_mut(index) {
+                Some(merged) => merge_candidate(merged, candidate),
+                None => response.candidates.push(candidate.clone()),
+            }
+        }
+        merge_usage(&mut response.usage_metadata, &chunk.usage_metadata);
+        if chunk.prompt_feedback.is_some() {
+            response.prompt_feedback = chunk.prompt_feedback.clone();
+        }
+        if chunk.model_version.is_some() {
+            response.model_version = chunk.model_version.clone();
+        }
+        if chunk.response_id.is_some() {
+            response.response_id = chunk.response_id.clone();
+        }
+    }
+
+    /// Returns the merged response; it has no candidates if no chunks were pushed.
+    pub fn finish(self) -> GeminiResponse {
+        self.response.unwrap_or_else(|| GeminiResponse {
+            candidates: Vec::new(),
+            usage_metadata: UsageMetadata::default(),
+            prompt_feedback: None,
+            model_version: None,
+            response_id: None,
+        })
+    }
+}
+
+fn merge_candidate(merged: &mut Candidate, chunk: &Candidate) {
+    for part in &chunk.content.parts {
+        match (merged.content.parts.last_mut(), part) {
+            (Some(Part::Text(text)), Part::Text(more)) => text.push_str(more),
+            _ => merged.content.parts.push(part.clone()),
+        }
+    }
+    if let Some(citations) = &chunk.citation_metadata {
+        match &mut merged.citation_metadata {
+            Some(merged) => merged
+                .citation_sources
+                .extend(citations.citation_sources.iter().cloned()),
+            None => merged.citation_metadata = Some(citations.clone()),
+        }
+    }
+    if let Some(attributions) = &chunk.grounding_attributions {
+        merged
+            .grounding_attributions
+            .get_or_insert_with(Vec::new)
+            .extend(attributions.iter().cloned());
+    }
+    if chunk.grounding_metadata.is_some() {
+        merged.grounding_metadata = chunk.grounding_metadata.clone();
+    }
+    if chunk.finish_reason.is_some() {
+        merged.finish_reason = chunk.finish_reason.clone();
+    }
+    if chunk.safety_ratings.is_some() {
+        merged.safety_ratings = chunk.safety_ratings.clone();
+    }
+}
+
+fn merge_usage(merged: &mut UsageMetadata, chunk: &UsageMetadata) {
+    if chunk.prompt_token_count.is_some() {
+        merged.prompt_token_count = chunk.prompt_token_count;
+    }
+    if chunk.cached_content_token_count.is_some() {
+        merged.cached_content_token_count = chunk.cached_content_token_count;
+    }
+    if chunk.candidates_token_count.is_some() {
+        merged.candidates_token_count = chunk.candidates_token_count;
+    }
+    if merged.prompt_token_count.is_some() || merged.candidates_token_count.is_some() {
+        merged.total_token_count = Some(merged.prompt_tokens() + merged.candidate_tokens());
+    }
+}
+
+/// Drives a text stream from `connect`, reconnecting when it fails with a retryable error.
+///
+/// Generation can't be resumed server-side, so a reconnect restarts it from scratch.
+/// Text already yielded before the failure is skipped when it is generated again,
+/// so callers see each character once (assuming the restarted output repeats the
+/// same prefix, which holds for deterministic configurations).
+pub(crate) fn resilient_text_stream<F, Fut, S>(
+    connect: F,
+    max_restarts: usize,
+) -> impl Stream<Item = Result<String, GeminiError>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<S, GeminiError>>,
+    S: Stream<Item = Result<GeminiResponse, GeminiError>>,
+{
+    let state = ResilientState {
+        connect,
+        current: None,
+        yielded: 0,
+        received: 0,
+        restarts: 0,
+        max_restarts,
+        done: false,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        while !state.done {
+            let current = match state.current.as_mut() {
+                Some(current) => current,
+                None => match (state.connect)().await {
+                    Ok(stream) => state.current.insert(Box::pin(stream)),
+                    Err(err) => {
+                        if let Some(err) = state.restart_or(err) {
+                            return Some((Err(err), state));
+                        }
+                        continue;
+                    }
+                },
+            };
+
+            match current.next().await {
+                Some(Ok(response)) => {
+                    let text = response
+                        .candidates
+                        .first()
+                        .and_then(|candidate| candidate.text())
+                        .unwrap_or_default();
+                    let length = text.chars().count();
+                    // skip whatever was already yielded before a restart
+                    let skip = state.yielded.saturating_sub(state.received);
+                    state.received += length;
+                    if skip < length {
+                        state.yielded = state.received;
+                        return Some((Ok(text.chars().skip(skip).collect()), state));
+                    }
+                }
+                Some(Err(err)) => {
+                    if let Some(err) = state.restart_or(err) {
+                        return Some((Err(err), state));
+                    }
+                }
+                None => state.done = true,
+            }
+        }
+        None
+    })
+}
+
+struct ResilientState<F, S> {
+    connect: F,
+    current: Option<Pin<Box<S>>>,
+    /// Characters yielded to the caller so far.
+    yielded: usize,
+    /// Characters received in the current attempt.
+    received: usize,
+    restarts: usize,
+    max_restarts: usize,
+    done: bool,
+}
+
+impl<F, S> ResilientState<F, S> {
+    /// Prepares a restart after `err`, or returns it if it should end the stream.
+    fn restart_or(&mut self, err: GeminiError) -> Option<GeminiError> {
+        if err.is_retryable() && self.restarts < self.max_restarts {
+            self.restarts += 1;
+            #[cfg(feature = "tracing")]
+            tracing::warn!(attempt = self.restarts, error = %err.message, "restarting the stream");
+            self.current = None;
+            self.received = 0;
+            None
+        } else {
+            self.done = true;
+            Some(err)
+        }
+    }
+}
+
+/// The format the API uses to deliver streamed responses.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFormat {
+    /// Server-sent events (`alt=sse`), one `data:` line per response.
+    #[default]
+    Sse,
+    /// A single JSON array whose elements arrive over time.
+    JsonArray,
+}
+
+/// Splits the body of a `streamGenerateContent` response into the JSON of each response.
+#[derive(Debug)]
+pub(crate) enum StreamDecoder {
+    Sse(SseDecoder),
+    JsonArray(JsonObjectDecoder),
+}
+
+impl StreamDecoder {
+    pub(crate) fn new(format: StreamFormat) -> Self {
+        match format {
+            StreamFormat::Sse => StreamDecoder::Sse(SseDecoder::default()),
+            StreamFormat::JsonArray => StreamDecoder::JsonArray(JsonObjectDecoder::default()),
+        }
+    }
+
+    /// Adds a chunk of bytes, returning every response completed by it.
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<Result<String, GeminiError>> {
+        match self {
+            StreamDecoder::Sse(decoder) => decoder.push(chunk),
+            StreamDecoder::JsonArray(decoder) => decoder.push(chunk),
+        }
+    }
+
+    /// Returns the response left over once the body has ended, if any.
+    pub(crate) fn finish(&mut self) -> Vec<Result<String, GeminiError>> {
+        match self {
+            StreamDecoder::Sse(decoder) => decoder.finish(),
+            // An unfinished object can't be parsed anyway.
+            StreamDecoder::JsonArray(_) => Vec::new(),
+        }
+    }
+}
+
+/// Parses a server-sent events stream into the payloads of its events.
+///
+/// Bytes are buffered until a whole line has arrived. The `data:` lines of an event
+/// are joined, and the event is emitted at the blank line that ends it, or by
+/// [SseDecoder::finish] when the stream ends without one. Other fields, comments
+/// (keep-alives) and empty events are ignored.
+#[derive(Debug, Default)]
+pub(crate) struct SseDecoder {
+    buffer: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl SseDecoder {
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<Result<String, GeminiError>> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let mut line: Vec<u8> = self.buffer.drain(..=end).collect();
+            line.pop();
+            self.line(line, &mut events);
+        }
+
+        events
+    }
+
+    /// Emits the last event of a stream that ended without a blank line after it.
+    pub(crate) fn finish(&mut self) -> Vec<Result<String, GeminiError>> {
+        let mut events = Vec::new();
+        let line = std::mem::take(&mut self.buffer);
+        self.line(line, &mut events);
+        self.line(Vec::new(), &mut events);
+        events
+    }
+
+    /// Handles one line, without its line feed.
+    fn line(&mut self, mut line: Vec<u8>, events: &mut Vec<Result<String, GeminiError>>) {
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+
+        if line.is_empty() {
+            if !self.data.is_empty() {
+                let data = std::mem::take(&mut self.data);
+                events.push(
+                    String::from_utf8(data).map_err(|err| GeminiError::message(&err.to_string())),
+                );
+            }
+        } else if let Some(value) = line.strip_prefix(b"data:") {
+            if !self.data.is_empty() {
+                self.data.push(b'\n');
+            }
+            self.data
+                .extend_from_slice(value.strip_prefix(b" ").unwrap_or(value));
+        }
+    }
+}
+
+/// Splits the byte stream of a `streamGenerateContent` response into its JSON objects.
+///
+/// The response is a JSON array delivered in arbitrary network chunks, so an object
+/// (or a multi-byte UTF-8 character inside it) may be split across chunks. Bytes are
+/// buffered until a whole top-level object has arrived. Scanning bytes is safe because
+/// every structural JSON character is ASCII, which never occurs inside a multi-byte character.
+#[derive(Debug, Default)]
+pub(crate) struct JsonObjectDecoder {
+    buffer: Vec<u8>,
+    /// How far into `buffer` has been scanned.
+    position: usize,
+    /// Where the object currently being read starts in `buffer`.
+    start: Option<usize>,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonObjectDecoder {
+    /// Adds a chunk of bytes, returning every object completed by it.
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<Result<String, GeminiError>> {
+        self.buffer.extend_from_slice(chunk);
+        let mut objects = Vec::new();
+
+        while self.position < self.buffer.len() {
+            let byte = self.buffer[self.position];
+            self.position += 1;
+
+            if self.start.is_none() {
+                // between objects: skip the array's `[`, `,`, `]` and whitespace
+                if byte == b'{' {
+                    self.start = Some(self.position - 1);
+                    self.depth = 1;
+                }
+                continue;
+            }
+
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        let start = self.start.take().unwrap_or_default();
+                        let object = self.buffer[start..self.position].to_vec();
+                        objects.push(
+                            String::from_utf8(object)
+                                .map_err(|err| GeminiError::message(&err.to_string())),
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // drop everything that has been fully consumed
+        let consumed = self.start.unwrap_or(self.position);
+        self.buffer.drain(..consumed);
+        self.position -= consumed;
+        if let Some(start) = self.start.as_mut() {
+            *start -= consumed;
+        }
+
+        objects
+    }
+}
+
+/// The text of the first candidate of a streamed chunk, empty if there is none.
+fn chunk_text(response: &GeminiResponse) -> String {
+    response
+        .candidates
+        .first()
+        .and_then(|candidate| candidate.text())
+        .unwrap_or_default()
+}
+
+/// Returns what the cumulative chunk `text` adds to the `previous` one.
+fn text_delta(previous: &mut String, text: String) -> String {
+    let delta = text
+        .strip_prefix(previous.as_str())
+        .unwrap_or(&text)
+        .to_string();
+    *previous = text;
+    delta
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::{api::FinishReason, content::Content, error::GeminiErrorKind, mock::MockServer};
+
+    fn chunk(text: &str) -> Result<GeminiResponse, GeminiError> {
+        Ok(serde_json::from_value(serde_json::json!({
+            "candidates": [{ "content": { "role": "model", "parts": [{ "text": text }] } }],
+            "usageMetadata": {}
+        }))
+        .unwrap())
+    }
+
+    fn decode(chunks: &[&[u8]]) -> Vec<String> {
+        let mut decoder = JsonObjectDecoder::default();
+        chunks
+            .iter()
+            .flat_map(|chunk| decoder.push(chunk))
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn decoder_splits_array_into_objects() {
+        let objects = decode(&[b"[{\"a\": \"}\\\"\"", b", \"b\": [{}]}\n", b",{\"c\": 1}]"]);
+        assert_eq!(objects, ["{\"a\": \"}\\\"\", \"b\": [{}]}", "{\"c\": 1}"]);
+    }
+
+    #[test]
+    fn decoder_handles_split_multibyte_characters() {
+        let json = "[{\"text\": \"caf\u{e9} \u{1f600}\"}]".as_bytes();
+        let e_acute = json.iter().position(|&b| b == 0xc3).unwrap();
+        let emoji = json.iter().position(|&b| b == 0xf0).unwrap();
+
+        let objects = decode(&[
+            &json[..e_acute + 1],
+            &json[e_acute + 1..emoji + 2],
+            &json[emoji + 2..],
+        ]);
+        assert_eq!(objects, ["{\"text\": \"caf\u{e9} \u{1f600}\"}"]);
+    }
+
+    #[test]
+    fn sse_decoder_splits_events() {
+        let mut decoder = SseDecoder::default();
+        let mut events = Vec::new();
+        for chunk in [
+            &b"data: {\"a\": 1}\r\n\r\n: keep-alive\r\n\r\ndata: {\"b\""[..],
+            b": 2}\n",
+            b"\n\ndata: {\"c\":\ndata: 3}\n\n",
+        ] {
+            events.extend(decoder.push(chunk).into_iter().map(Result::unwrap));
+        }
+        assert_eq!(events, ["{\"a\": 1}", "{\"b\": 2}", "{\"c\":\n3}"]);
+    }
+
+    #[test]
+    fn sse_decoder_flushes_the_last_event() {
+        let mut decoder = SseDecoder::default();
+        let events = decoder.push(b"data: {\"a\": 1}\n\ndata: {\"b\": 2}");
+        assert_eq!(events.len(), 1);
+        let events: Vec<String> = decoder.finish().into_iter().map(Result::unwrap).collect();
+        assert_eq!(events, ["{\"b\": 2}"]);
+        assert!(decoder.finish().is_empty());
+
+        let mut decoder = SseDecoder::default();
+        decoder.push(b"data: {\"c\": 3}\n");
+        let events: Vec<String> = decoder.finish().into_iter().map(Result::unwrap).collect();
+        assert_eq!(events, ["{\"c\": 3}"]);
+    }
+
+    #[tokio::test]
+    async fn resilient_stream_restarts_after_disconnect() {
+        let attempts = Cell::new(0);
+        let connect = || {
+            attempts.set(attempts.get() + 1);
+            let chunks = if attempts.get() == 1 {
+                vec![
+                    chunk("Hello, "),
+                    Err(GeminiError {
+                        kind: GeminiErrorKind::Connection,
+                        message: "connection reset".to_string(),
+                        context: None,
+                    }),
+                ]
+            } else {
+                vec![chunk("Hel"), chunk("lo, wor"), chunk("ld")]
+            };
+            ready(Ok(stream::iter(chunks)))
+        };
+
+        let deltas: Vec<String> = resilient_text_stream(connect, 1)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(attempts.get(), 2);
+        assert_eq!(deltas, ["Hello, ", "wor", "ld"]);
+    }
+
+    #[tokio::test]
+    async fn resilient_stream_gives_up_after_max_restarts() {
+        let connect = || {
+            ready(Ok(stream::iter(vec![Err(GeminiError {
+                kind: GeminiErrorKind::Connection,
+                message: "connection reset".to_string(),
+                context: None,
+            })])))
+        };
+
+        let results: Vec<_> = resilient_text_stream(connect, 2).collect().await;
+        assert_eq!(results.len(), 1);
+        assert!(
+            matches!(results[0], Err(ref err) if matches!(err.kind, GeminiErrorKind::Connection))
+        );
+    }
+
+    #[tokio::test]
+    async fn parts_in_order_across_chunks() {
+        let call: Result<GeminiResponse, GeminiError> =
+            Ok(serde_json::from_value(serde_json::json!({
+                "candidates": [{ "content": { "role": "model", "parts": [
+                    { "text": "Checking" },
+                    { "functionCall": { "name": "weather" } }
+                ] } }],
+                "usageMetadata": {}
+            }))
+            .unwrap());
+
+        let parts: Vec<Part> = stream::iter(vec![chunk("Let me "), call, chunk("done")])
+            .parts_stream()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        assert_eq!(parts.len(), 4);
+        assert!(matches!(&parts[0], Part::Text(text) if text == "Let me "));
+        assert!(matches!(&parts[1], Part::Text(text) if text == "Checking"));
+        assert!(matches!(&parts[2], Part::FunctionCall { name, .. } if name == "weather"));
+        assert!(matches!(&parts[3], Part::Text(text) if text == "done"));
+    }
+
+    #[tokio::test]
+    async fn incremental_chunks() {
+        let deltas: Vec<String> = stream::iter(vec![chunk("Hel"), chunk("lo, "), chunk("world")])
+            .text_deltas()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(deltas, ["Hel", "lo, ", "world"]);
+    }
+
+    #[tokio::test]
+    async fn incremental_chunks_repeating_a_prefix() {
+        let deltas: Vec<String> =
+            stream::iter(vec![chunk("ha"), chunk("ha"), chunk(" "), chunk(" the")])
+                .text_deltas()
+                .map(Result::unwrap)
+                .collect()
+                .await;
+        assert_eq!(deltas, ["ha", "ha", " ", " the"]);
+    }
+
+    #[tokio::test]
+    async fn cumulative_chunks() {
+        let deltas: Vec<String> = stream::iter(vec![
+            chunk("Hel"),
+            chunk("Hello, "),
+            chunk("Hello, "),
+            chunk("Hello, world"),
+        ])
+        .cumulative_text_deltas()
+        .map(Result::unwrap)
+        .collect()
+        .await;
+        assert_eq!(deltas, ["Hel", "lo, ", "world"]);
+    }
+
+    #[test]
+    fn accumulator_merges_chunks() {
+        let chunks: Vec<GeminiResponse> = [
+            serde_json::json!({
+                "candidates": [{ "content": { "role": "model", "parts": [{ "text": "The answer" }] } }],
+                "usageMetadata": { "promptTokenCount": 10, "candidatesTokenCount": 2 },
+                "modelVersion": "gemini-1.5-flash-002"
+            }),
+            serde_json::json!({
+                "candidates": [{
+                    "content": { "role": "model", "parts": [{ "text": " is" }] },
+                    "citationMetadata": { "citationSources": [{ "startIndex": 0, "endIndex": 3, "uri": "a" }] }
+                }],
+                "usageMetadata": { "promptTokenCount": 10, "candidatesTokenCount": 3 }
+            }),
+            serde_json::json!({
+                "candidates": [{
+                    "content": { "role": "model", "parts": [{ "text": " 42." }] },
+                    "citationMetadata": { "citationSources": [{ "startIndex": 4, "endIndex": 9, "uri": "b" }] },
+                    "finishReason": "STOP"
+                }],
+                "usageMetadata": { "promptTokenCount": 10, "candidatesTokenCount": 6 }
+            }),
+        ]
+        .into_iter()
+        .map(|json| serde_json::from_value(json).unwrap())
+        .collect();
+
+        let mut accumulator = ResponseAccumulator::new();
+        for chunk in &chunks {
+            accumulator.push(chunk);
+        }
+        let response = accumulator.finish();
+
+        let candidate = &response.candidates[0];
+        assert_eq!(candidate.content.parts.len(), 1);
+        assert_eq!(response.text().unwrap(), "The answer is 42.");
+        let uris: Vec<_> = candidate
+            .citation_metadata
+            .as_ref()
+            .unwrap()
+            .citation_sources
+            .iter()
+            .map(|source| source.uri.as_str())
+            .collect();
+        assert_eq!(uris, ["a", "b"]);
+        assert!(matches!(candidate.finish_reason, Some(FinishReason::Stop)));
+        assert_eq!(response.usage_metadata.prompt_tokens(), 10);
+        assert_eq!(response.usage_metadata.candidate_tokens(), 6);
+        assert_eq!(response.usage_metadata.total_tokens(), 16);
+        assert_eq!(
+            response.model_version.as_deref(),
+            Some("gemini-1.5-flash-002")
+        );
+
+        assert!(ResponseAccumulator::new().finish().candidates.is_empty());
+    }
+
+    #[tokio::test]
+    async fn function_call_fires_mid_stream() {
+        let body = concat!(
+            "data: {\"candidates\": [{\"content\": {\"role\": \"model\", \"parts\": ",
+            "[{\"functionCall\": {\"name\": \"get_weather\", \"args\": {\"city\": \"Oslo\"}}}]}}], ",
+            "\"usageMetadata\": {}}\n\n",
+            "data: {\"candidates\": [{\"content\": {\"role\": \"model\", \"parts\": ",
+            "[{\"text\": \"Checking...\"}]}}], \"usageMetadata\": {}}\n\n",
+        );
+        let server = MockServer::start(vec![(200, body.to_string())]).await;
+        let model = server.model().build();
+
+        let mut calls = Vec::new();
+        let stream = model
+            .generate_content_streamed(vec![Content::user("Weather in Oslo?")])
+            .await
+            .unwrap();
+        let chunks: Vec<_> = stream
+            .on_function_call(|name, args| calls.push((name.to_string(), args.cloned())))
+            .collect()
+            .await;
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(
+            calls,
+            [(
+                "get_weather".to_string(),
+                Some(serde_json::json!({ "city": "Oslo" }))
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn final_usage_comes_from_the_last_chunk() {
+        let body = concat!(
+            "data: {\"candidates\": [{\"content\": {\"role\": \"model\", \"parts\": ",
+            "[{\"text\": \"Hello\"}]}}], \"usageMetadata\": {\"promptTokenCount\": 4}}\n\n",
+            "data: {\"candidates\": [{\"content\": {\"role\": \"model\", \"parts\": ",
+            "[{\"text\": \" there\"}]}, \"finishReason\": \"STOP\"}], \"usageMetadata\": ",
+            "{\"promptTokenCount\": 4, \"candidatesTokenCount\": 2, \"totalTokenCount\": 6}}\n\n",
+        );
+        let server = MockServer::start(vec![(200, body.to_string())]).await;
+        let model = server.model().build();
+
+        let mut stream = model
+            .generate_content_streamed(vec![Content::user("Hi")])
+            .await
+            .unwrap()
+            .track_usage();
+        assert!(stream.final_usage().is_none());
+        while let Some(chunk) = stream.next().await {
+            chunk.unwrap();
+        }
+
+        let usage = stream.final_usage().unwrap();
+        assert_eq!(usage.candidate_tokens(), 2);
+        assert_eq!(usage.total_tokens(), 6);
+    }
+
+    #[tokio::test]
+    async fn json_stream_parses_buffered_fragments() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Recipe {
+            name: String,
+            servings: u8,
+        }
+
+        let chunks = vec![
+            chunk("{\"na"),
+            chunk("me\": \"Pan"),
+            chunk("cakes\", \"serv"),
+            chunk("ings\": 4}"),
+        ];
+        let recipes: Vec<_> = stream::iter(chunks).json_stream::<Recipe>().collect().await;
+        assert_eq!(recipes.len(), 1);
+        assert_eq!(
+            recipes[0].as_ref().unwrap(),
+            &Recipe {
+                name: "Pancakes".to_string(),
+                servings: 4
+            }
+        );
+
+        let truncated: Vec<_> = stream::iter(vec![chunk("{\"name\": \"Pan")])
+            .json_stream::<Recipe>()
+            .collect()
+            .await;
+        assert_eq!(truncated.len(), 1);
+        assert!(truncated[0].is_err());
+    }
+
+    #[tokio::test]
+    async fn json_stream_keeps_chunks_repeating_the_buffer() {
+        let values: Vec<Vec<Vec<i32>>> = stream::iter(vec![chunk("["), chunk("[1, 2], [3, 4]]")])
+            .json_stream()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(values, [vec![vec![1, 2], vec![3, 4]]]);
+    }
+
+    #[tokio::test]
+    async fn json_stream_waits_for_the_end_of_a_scalar() {
+        let values: Vec<_> = stream::iter(vec![chunk("12"), chunk("3")])
+            .json_stream::<i32>()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(values, [123]);
+    }
+}